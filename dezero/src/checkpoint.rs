@@ -0,0 +1,308 @@
+use ndarray::{arr0, arr1, ArrayD};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use crate::dataloaders::DataLoader;
+use crate::datasets::Dataset;
+use crate::layers::{self, Layer};
+use crate::npy;
+use crate::optimizers::Optimizer;
+use crate::random;
+use crate::schedulers::{Scheduler, SchedulerState};
+
+fn scalar(v: f64) -> ArrayD<f64> {
+    arr0(v).into_dyn()
+}
+
+/// Generator states are full `u64`s, which `f64` cannot hold exactly, so
+/// they are stored as two 32-bit halves.
+fn pack_u64(v: u64) -> ArrayD<f64> {
+    arr1(&[(v >> 32) as f64, (v & 0xffff_ffff) as f64]).into_dyn()
+}
+
+fn unpack_u64(a: &ArrayD<f64>) -> io::Result<u64> {
+    match a.iter().map(|&x| x as u64).collect::<Vec<_>>()[..] {
+        [hi, lo] => Ok((hi << 32) | lo),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packed u64 needs 2 values, got {}", a.len()),
+        )),
+    }
+}
+
+/// Everything needed to pick an interrupted run back up: parameters,
+/// optimizer and scheduler state, progress counters, the global RNG and the
+/// data loader's shuffling state.
+///
+/// Build one with the `with_*` methods and `save` it; after `load`, call the
+/// matching `restore_*` methods on freshly constructed (and `setup`) objects.
+/// Everything is stored as `f64` arrays in a single `.npz`, so restoring is
+/// exact and the resumed run matches an uninterrupted one bit for bit.
+pub struct Checkpoint {
+    pub epoch: u64,
+    pub step: u64,
+    arrays: BTreeMap<String, ArrayD<f64>>,
+}
+
+impl Checkpoint {
+    /// Records the counters and the current global RNG state.
+    pub fn new(epoch: u64, step: u64) -> Self {
+        let mut arrays = BTreeMap::new();
+        arrays.insert("random".to_string(), pack_u64(random::get_state()));
+        Checkpoint {
+            epoch,
+            step,
+            arrays,
+        }
+    }
+
+    fn put(&mut self, prefix: &str, entries: impl IntoIterator<Item = (String, ArrayD<f64>)>) {
+        for (k, a) in entries {
+            self.arrays.insert(format!("{}/{}", prefix, k), a);
+        }
+    }
+
+    fn section(&self, prefix: &str) -> Vec<(String, ArrayD<f64>)> {
+        let prefix = format!("{}/", prefix);
+        self.arrays
+            .iter()
+            .filter_map(|(k, a)| Some((k.strip_prefix(&prefix)?.to_string(), a.clone())))
+            .collect()
+    }
+
+    fn get(&self, key: &str) -> io::Result<&ArrayD<f64>> {
+        self.arrays.get(key).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("checkpoint has no '{}'", key),
+            )
+        })
+    }
+
+    pub fn with_model(mut self, model: &dyn Layer) -> Self {
+        let params = model
+            .named_params()
+            .into_iter()
+            .filter(|(_, p)| p.is_init())
+            .map(|(name, p)| (name, p.borrow().data.clone()));
+        self.put("model", params);
+        self
+    }
+
+    pub fn with_optimizer(mut self, optimizer: &dyn Optimizer) -> Self {
+        self.put("optimizer", optimizer.state());
+        self
+    }
+
+    pub fn with_scheduler(mut self, scheduler: &dyn Scheduler) -> Self {
+        let state = scheduler.state().into_iter().map(|(k, v)| (k, scalar(v)));
+        self.put("scheduler", state);
+        self
+    }
+
+    /// Records the loader's order and position. To checkpoint mid-epoch,
+    /// drive the loader with `while let Some((x, t)) = loader.next()`: a
+    /// `for (x, t) in &mut loader` loop keeps it mutably borrowed, so it
+    /// cannot be passed here from inside the loop body.
+    pub fn with_loader<D: Dataset>(mut self, loader: &DataLoader<D>) -> Self {
+        let index = loader.index.iter().map(|&i| i as f64).collect::<Vec<_>>();
+        self.put(
+            "loader",
+            [
+                ("rng".to_string(), pack_u64(loader.rng.state())),
+                ("index".to_string(), arr1(&index).into_dyn()),
+                ("iteration".to_string(), scalar(loader.iteration as f64)),
+            ],
+        );
+        self
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut arrays: Vec<(String, ArrayD<f64>)> = vec![
+            ("epoch".to_string(), scalar(self.epoch as f64)),
+            ("step".to_string(), scalar(self.step as f64)),
+        ];
+        arrays.extend(self.arrays.iter().map(|(k, a)| (k.clone(), a.clone())));
+        npy::save_npz(path, &arrays)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut arrays: BTreeMap<String, ArrayD<f64>> = npy::load_npz(path)?.into_iter().collect();
+        let mut counter = |key: &str| {
+            arrays
+                .remove(key)
+                .and_then(|a| a.iter().next().copied())
+                .map(|v| v as u64)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("checkpoint has no '{}'", key),
+                    )
+                })
+        };
+        let epoch = counter("epoch")?;
+        let step = counter("step")?;
+        Ok(Checkpoint {
+            epoch,
+            step,
+            arrays,
+        })
+    }
+
+    /// Same rules as `Layer::load_weights`: every parameter must be present
+    /// and nothing changes unless all of them match.
+    pub fn restore_model(&self, model: &dyn Layer) -> io::Result<()> {
+        layers::assign_params(
+            &model.named_params(),
+            self.section("model"),
+            Path::new("checkpoint"),
+        )
+    }
+
+    pub fn restore_optimizer(&self, optimizer: &mut dyn Optimizer) {
+        optimizer.load_state(&self.section("optimizer").into_iter().collect());
+    }
+
+    /// Loads the scheduler state and writes its rate into `optimizer`.
+    pub fn restore_scheduler(&self, scheduler: &mut dyn Scheduler, optimizer: &mut dyn Optimizer) {
+        let state: SchedulerState = self
+            .section("scheduler")
+            .into_iter()
+            .filter_map(|(k, a)| Some((k, *a.iter().next()?)))
+            .collect();
+        scheduler.load_state(&state);
+        scheduler.apply(optimizer);
+    }
+
+    /// Puts the loader back mid-epoch: same order, same next batch.
+    pub fn restore_loader<D: Dataset>(&self, loader: &mut DataLoader<D>) -> io::Result<()> {
+        let index: Vec<usize> = self
+            .get("loader/index")?
+            .iter()
+            .map(|&i| i as usize)
+            .collect();
+        if index.len() != loader.dataset.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "checkpoint loader covers {} samples but the dataset has {}",
+                    index.len(),
+                    loader.dataset.len()
+                ),
+            ));
+        }
+        loader.rng.set_state(unpack_u64(self.get("loader/rng")?)?);
+        loader.iteration = self.get("loader/iteration")?.iter().sum::<f64>() as usize;
+        loader.index = index;
+        Ok(())
+    }
+
+    /// Restores the global generator used for weight initialization.
+    pub fn restore_random(&self) -> io::Result<()> {
+        random::set_state(unpack_u64(self.get("random")?)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::VariableExt;
+    use crate::datasets::ArrayDataset;
+    use crate::functions as F;
+    use crate::layers::{BatchNorm, Dropout, Linear};
+    use crate::models::{Sequential, MLP};
+    use crate::optimizers::Adam;
+    use crate::schedulers::StepLR;
+
+    struct Run {
+        model: Sequential,
+        optimizer: Adam,
+        scheduler: StepLR,
+        loader: DataLoader<ArrayDataset>,
+    }
+
+    impl Run {
+        fn new(seed: u64) -> Self {
+            random::seed(seed);
+            let model = Sequential::new(vec![
+                Box::new(MLP::new(&[8, 8], F::relu)),
+                Box::new(BatchNorm::new()),
+                Box::new(Dropout::new(0.3)),
+                Box::new(Linear::new(3)),
+            ]);
+            let mut optimizer = Adam::new(0.01);
+            optimizer.setup(&model);
+            let x = random::Rng::new(100).randn(&[20, 4]);
+            let t = ArrayD::from_shape_fn(ndarray::IxDyn(&[20]), |i| (i[0] % 3) as f64);
+            let loader = DataLoader::new(ArrayDataset::new(x, t), 6, true).seed(seed);
+            Run {
+                model,
+                optimizer,
+                scheduler: StepLR::new(0.01, 3, 0.5),
+                loader,
+            }
+        }
+
+        /// Trains on at most `steps` batches, stopping at the end of the epoch.
+        fn train(&mut self, steps: usize) {
+            let mut done = 0;
+            while done < steps {
+                let Some((x, t)) = self.loader.next() else {
+                    break;
+                };
+                let loss = F::softmax_cross_entropy(&self.model.forward(&x), &t);
+                self.model.cleargrads();
+                loss.backward();
+                self.optimizer.update();
+                self.scheduler.step(&mut self.optimizer);
+                done += 1;
+            }
+        }
+
+        fn weights(&self) -> Vec<ArrayD<f64>> {
+            self.model.params().map(|p| p.data()).collect()
+        }
+    }
+
+    #[test]
+    fn resume_mid_epoch_is_bit_identical() {
+        let path = std::env::temp_dir().join(format!("dezero-{}-resume.npz", std::process::id()));
+        let mut run = Run::new(0);
+        run.train(usize::MAX);
+        run.train(2);
+        Checkpoint::new(1, 6)
+            .with_model(&run.model)
+            .with_optimizer(&run.optimizer)
+            .with_scheduler(&run.scheduler)
+            .with_loader(&run.loader)
+            .save(&path)
+            .unwrap();
+        run.train(usize::MAX);
+        run.train(usize::MAX);
+
+        let mut resumed = Run::new(7);
+        let ckpt = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((ckpt.epoch, ckpt.step), (1, 6));
+        ckpt.restore_model(&resumed.model).unwrap();
+        ckpt.restore_optimizer(&mut resumed.optimizer);
+        ckpt.restore_scheduler(&mut resumed.scheduler, &mut resumed.optimizer);
+        ckpt.restore_loader(&mut resumed.loader).unwrap();
+        ckpt.restore_random().unwrap();
+        resumed.train(usize::MAX);
+        resumed.train(usize::MAX);
+        assert_eq!(run.weights(), resumed.weights());
+    }
+
+    #[test]
+    fn malformed_generator_state_is_invalid_data() {
+        let mut ckpt = Checkpoint::new(0, 0);
+        ckpt.arrays
+            .insert("random".to_string(), arr1(&[1.0]).into_dyn());
+        let err = ckpt.restore_random().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,515 @@
+use ndarray::{Array, Array2, ArrayD, Axis, Dimension, IxDyn};
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+use crate::utils;
+
+pub struct Config {
+    pub enable_backprop: bool,
+    /// Mode-dependent functions such as `dropout` check this flag.
+    pub train: bool,
+}
+
+thread_local! {
+    static CONFIG: RefCell<Config> = const {
+        RefCell::new(Config {
+            enable_backprop: true,
+            train: true,
+        })
+    };
+}
+
+pub fn enable_backprop() -> bool {
+    CONFIG.with(|c| c.borrow().enable_backprop)
+}
+
+/// Restores the previous backprop setting when dropped.
+pub struct NoGradGuard {
+    prev: bool,
+}
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        CONFIG.with(|c| c.borrow_mut().enable_backprop = self.prev);
+    }
+}
+
+/// Disables graph construction until the returned guard goes out of scope.
+pub fn no_grad() -> NoGradGuard {
+    let prev = CONFIG.with(|c| std::mem::replace(&mut c.borrow_mut().enable_backprop, false));
+    NoGradGuard { prev }
+}
+
+pub fn is_training() -> bool {
+    CONFIG.with(|c| c.borrow().train)
+}
+
+/// Restores the previous train/test mode when dropped.
+pub struct TestModeGuard {
+    prev: bool,
+}
+
+impl Drop for TestModeGuard {
+    fn drop(&mut self) {
+        CONFIG.with(|c| c.borrow_mut().train = self.prev);
+    }
+}
+
+/// Switches to inference behaviour until the returned guard goes out of scope.
+pub fn test_mode() -> TestModeGuard {
+    let prev = CONFIG.with(|c| std::mem::replace(&mut c.borrow_mut().train, false));
+    TestModeGuard { prev }
+}
+
+pub struct Variable {
+    pub data: ArrayD<f64>,
+    pub grad: Option<ArrayD<f64>>,
+    /// Gradient that touches only some rows, left by lookups such as
+    /// `embed_id` in place of a dense `grad`.
+    pub sparse_grad: Option<SparseGrad>,
+    pub creator: Option<Rc<RefCell<GradientFunction>>>,
+    pub generation: usize,
+    pub name: Option<String>,
+}
+
+impl fmt::Debug for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Variable")
+            .field("name", &self.name)
+            .field("data", &self.data)
+            .field("grad", &self.grad)
+            .field("sparse_grad", &self.sparse_grad)
+            .finish()
+    }
+}
+
+/// Gradient of a parameter along its first axis, stored only for the rows
+/// that received one: row `rows[i]` gets `values[i]`. A row may be listed
+/// more than once, in which case its entries add up.
+#[derive(Debug, Clone)]
+pub struct SparseGrad {
+    pub rows: Vec<usize>,
+    pub values: Array2<f64>,
+}
+
+impl SparseGrad {
+    pub fn push(&mut self, rows: &[usize], values: &Array2<f64>) {
+        self.rows.extend_from_slice(rows);
+        let stacked = ndarray::concatenate(Axis(0), &[self.values.view(), values.view()]);
+        self.values = stacked.expect("sparse gradient rows must share a width");
+    }
+
+    /// Sums repeated rows, returning the distinct rows in ascending order
+    /// with one gradient row each.
+    pub fn coalesce(&self) -> (Vec<usize>, Array2<f64>) {
+        let mut rows = self.rows.clone();
+        rows.sort_unstable();
+        rows.dedup();
+        let mut values = Array2::zeros((rows.len(), self.values.ncols()));
+        for (&r, g) in self.rows.iter().zip(self.values.outer_iter()) {
+            let i = rows.binary_search(&r).unwrap();
+            let mut dst = values.row_mut(i);
+            dst += &g;
+        }
+        (rows, values)
+    }
+
+    /// Adds the gradient into `dense`, a tensor of the parameter's shape.
+    pub fn add_to(&self, dense: &mut ArrayD<f64>) {
+        for (&r, g) in self.rows.iter().zip(self.values.outer_iter()) {
+            let mut dst = dense.index_axis_mut(Axis(0), r);
+            dst.iter_mut().zip(g.iter()).for_each(|(d, &v)| *d += v);
+        }
+    }
+}
+
+impl Variable {
+    pub fn new<D: Dimension>(value: Array<f64, D>) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Variable {
+            data: value.into_dyn(),
+            grad: None,
+            sparse_grad: None,
+            creator: None,
+            generation: 0,
+            name: None,
+        }))
+    }
+
+    pub fn with_name<D: Dimension>(value: Array<f64, D>, name: &str) -> Rc<RefCell<Self>> {
+        let var = Variable::new(value);
+        var.borrow_mut().name = Some(name.to_string());
+        var
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.data.shape()
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.data.ndim()
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn set_creator(&mut self, func: &Rc<RefCell<GradientFunction>>) {
+        self.generation = func.borrow().generation + 1;
+        self.creator = Some(func.clone());
+    }
+
+    pub fn cleargrad(&mut self) {
+        self.grad = None;
+        self.sparse_grad = None;
+    }
+
+    /// Forgets the function that produced this variable, making it a leaf.
+    pub fn unchain(&mut self) {
+        self.creator = None;
+    }
+}
+
+/// Graph operations that need the shared handle rather than the inner `Variable`.
+pub trait VariableExt {
+    fn backward(&self);
+    /// Cuts the graph behind this variable: every variable it was computed
+    /// from becomes a leaf. Used for truncated backpropagation through time,
+    /// so the graph built up by a recurrent layer can be freed.
+    fn unchain_backward(&self);
+    fn cleargrad(&self);
+    fn data(&self) -> ArrayD<f64>;
+    fn grad(&self) -> Option<ArrayD<f64>>;
+    fn shape(&self) -> Vec<usize>;
+}
+
+impl VariableExt for Rc<RefCell<Variable>> {
+    fn backward(&self) {
+        let creator = {
+            let mut y = self.borrow_mut();
+            if y.grad.is_none() {
+                y.grad = Some(ArrayD::ones(y.data.raw_dim()));
+            }
+            match &y.creator {
+                Some(c) => c.clone(),
+                None => return,
+            }
+        };
+
+        let mut seen = HashSet::new();
+        seen.insert(Rc::as_ptr(&creator));
+        let mut funcs = vec![creator];
+
+        while let Some(f) = funcs.pop() {
+            let (inputs, gxs) = {
+                let mut node = f.borrow_mut();
+                let GradientFunction {
+                    func,
+                    inputs,
+                    outputs,
+                    output_shapes,
+                    ..
+                } = &mut *node;
+                let gys: Vec<ArrayD<f64>> = outputs
+                    .iter()
+                    .zip(output_shapes.iter())
+                    .map(|(o, shape)| {
+                        o.upgrade()
+                            .and_then(|o| o.borrow().grad.clone())
+                            .unwrap_or_else(|| ArrayD::zeros(IxDyn(shape)))
+                    })
+                    .collect();
+                let borrows: Vec<_> = inputs.iter().map(|x| x.borrow()).collect();
+                let xs: Vec<&ArrayD<f64>> = borrows.iter().map(|x| &x.data).collect();
+                let gxs = func.backward(&xs, &gys);
+                drop(borrows);
+                (inputs.clone(), gxs)
+            };
+
+            for (x, gx) in inputs.iter().zip(gxs) {
+                let mut x = x.borrow_mut();
+                x.grad = Some(match x.grad.take() {
+                    Some(g) => g + gx,
+                    None => gx,
+                });
+                if let Some(c) = &x.creator {
+                    if seen.insert(Rc::as_ptr(c)) {
+                        funcs.push(c.clone());
+                        funcs.sort_by_key(|f| f.borrow().generation);
+                    }
+                }
+            }
+        }
+    }
+
+    fn unchain_backward(&self) {
+        let mut funcs: Vec<_> = self.borrow().creator.iter().cloned().collect();
+        while let Some(f) = funcs.pop() {
+            for x in f.borrow().inputs.iter() {
+                let mut x = x.borrow_mut();
+                if let Some(c) = x.creator.take() {
+                    funcs.push(c);
+                }
+            }
+        }
+    }
+
+    fn cleargrad(&self) {
+        self.borrow_mut().cleargrad();
+    }
+
+    fn data(&self) -> ArrayD<f64> {
+        self.borrow().data.clone()
+    }
+
+    fn grad(&self) -> Option<ArrayD<f64>> {
+        self.borrow().grad.clone()
+    }
+
+    fn shape(&self) -> Vec<usize> {
+        self.borrow().data.shape().to_vec()
+    }
+}
+
+/// A trainable variable. It shares the `Variable` machinery but is a distinct
+/// type so layers and optimizers can tell weights apart from activations.
+#[derive(Clone)]
+pub struct Parameter(Rc<RefCell<Variable>>);
+
+impl Parameter {
+    pub fn new<D: Dimension>(value: Array<f64, D>, name: &str) -> Self {
+        Parameter(Variable::with_name(value, name))
+    }
+
+    /// A parameter whose data is filled in later, e.g. on the first forward pass.
+    pub fn uninit(name: &str) -> Self {
+        Parameter::new(ArrayD::zeros(IxDyn(&[0])), name)
+    }
+
+    pub fn is_init(&self) -> bool {
+        !self.0.borrow().data.is_empty()
+    }
+
+    pub fn var(&self) -> &Rc<RefCell<Variable>> {
+        &self.0
+    }
+}
+
+impl Deref for Parameter {
+    type Target = Rc<RefCell<Variable>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Parameter({:?})", self.0.borrow())
+    }
+}
+
+/// A node in the computational graph: the function that produced some
+/// variables, holding its inputs strongly and its outputs weakly.
+pub struct GradientFunction {
+    pub func: Box<dyn Function>,
+    pub inputs: Vec<Rc<RefCell<Variable>>>,
+    pub outputs: Vec<Weak<RefCell<Variable>>>,
+    pub output_shapes: Vec<Vec<usize>>,
+    pub generation: usize,
+}
+
+pub trait Function {
+    fn call(self, inputs: &[&Rc<RefCell<Variable>>]) -> Vec<Rc<RefCell<Variable>>>
+    where
+        Self: Sized + 'static,
+    {
+        let mut func = self;
+        let ys = {
+            let borrows: Vec<_> = inputs.iter().map(|x| x.borrow()).collect();
+            let xs: Vec<&ArrayD<f64>> = borrows.iter().map(|x| &x.data).collect();
+            func.forward(&xs)
+        };
+        let outputs: Vec<Rc<RefCell<Variable>>> = ys.into_iter().map(Variable::new).collect();
+
+        if enable_backprop() {
+            let generation = inputs
+                .iter()
+                .map(|x| x.borrow().generation)
+                .max()
+                .unwrap_or(0);
+            let gf = Rc::new(RefCell::new(GradientFunction {
+                func: Box::new(func),
+                inputs: inputs.iter().map(|&x| x.clone()).collect(),
+                outputs: outputs.iter().map(Rc::downgrade).collect(),
+                output_shapes: outputs
+                    .iter()
+                    .map(|y| y.borrow().shape().to_vec())
+                    .collect(),
+                generation,
+            }));
+            for y in &outputs {
+                y.borrow_mut().set_creator(&gf);
+            }
+        }
+
+        outputs
+    }
+
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>>;
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>>;
+}
+
+pub(crate) fn call1<F: Function + 'static>(
+    f: F,
+    inputs: &[&Rc<RefCell<Variable>>],
+) -> Rc<RefCell<Variable>> {
+    f.call(inputs).remove(0)
+}
+
+pub struct Add;
+
+impl Function for Add {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0] + xs[1]]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![
+            utils::sum_to(&gys[0], xs[0].shape()),
+            utils::sum_to(&gys[0], xs[1].shape()),
+        ]
+    }
+}
+
+pub fn add(x0: &Rc<RefCell<Variable>>, x1: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Add, &[x0, x1])
+}
+
+pub struct Sub;
+
+impl Function for Sub {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0] - xs[1]]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![
+            utils::sum_to(&gys[0], xs[0].shape()),
+            utils::sum_to(&-&gys[0], xs[1].shape()),
+        ]
+    }
+}
+
+pub fn sub(x0: &Rc<RefCell<Variable>>, x1: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Sub, &[x0, x1])
+}
+
+pub struct Mul;
+
+impl Function for Mul {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0] * xs[1]]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![
+            utils::sum_to(&(&gys[0] * xs[1]), xs[0].shape()),
+            utils::sum_to(&(&gys[0] * xs[0]), xs[1].shape()),
+        ]
+    }
+}
+
+pub fn mul(x0: &Rc<RefCell<Variable>>, x1: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Mul, &[x0, x1])
+}
+
+pub struct Div;
+
+impl Function for Div {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0] / xs[1]]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gx0 = &gys[0] / xs[1];
+        let gx1 = &gys[0] * &(-xs[0] / &(xs[1] * xs[1]));
+        vec![
+            utils::sum_to(&gx0, xs[0].shape()),
+            utils::sum_to(&gx1, xs[1].shape()),
+        ]
+    }
+}
+
+pub fn div(x0: &Rc<RefCell<Variable>>, x1: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Div, &[x0, x1])
+}
+
+pub struct Neg;
+
+impl Function for Neg {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![-xs[0]]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![-&gys[0]]
+    }
+}
+
+pub fn neg(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Neg, &[x])
+}
+
+pub struct Pow {
+    c: f64,
+}
+
+impl Function for Pow {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let c = self.c;
+        vec![xs[0].mapv(|x| x.powf(c))]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let c = self.c;
+        vec![&gys[0] * &xs[0].mapv(|x| c * x.powf(c - 1.0))]
+    }
+}
+
+pub fn pow(x: &Rc<RefCell<Variable>>, c: f64) -> Rc<RefCell<Variable>> {
+    call1(Pow { c }, &[x])
+}
+
+/// Wraps a scalar as a 0-d variable, for mixing constants into expressions.
+pub fn scalar(value: f64) -> Rc<RefCell<Variable>> {
+    Variable::new(ndarray::arr0(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr1;
+
+    #[test]
+    fn unchain_backward_turns_upstream_into_leaves() {
+        let x = Variable::new(arr1(&[1.0, 2.0]));
+        let a = mul(&x, &scalar(2.0));
+        let b = add(&a, &x);
+        let y = mul(&b, &b);
+        y.unchain_backward();
+        for v in [&x, &a, &b] {
+            assert!(v.borrow().creator.is_none());
+        }
+        assert!(y.borrow().creator.is_some());
+
+        y.backward();
+        assert_eq!(b.grad().unwrap(), b.data() * 2.0);
+        assert!(a.grad().is_none());
+        assert!(x.grad().is_none());
+    }
+}
@@ -0,0 +1,143 @@
+use ndarray::{ArrayD, ArrayViewD, Axis};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::core::Variable;
+use crate::datasets::Dataset;
+use crate::random::Rng;
+
+/// Yields `(x, t)` mini-batches, each stacked along a new first axis.
+///
+/// Iteration stops at the end of an epoch and the loader resets itself
+/// (reshuffling if `shuffle` is set), so `for (x, t) in &mut loader`
+/// runs exactly one epoch each time.
+pub struct DataLoader<D: Dataset> {
+    pub dataset: D,
+    pub batch_size: usize,
+    pub shuffle: bool,
+    pub drop_last: bool,
+    pub rng: Rng,
+    pub index: Vec<usize>,
+    pub iteration: usize,
+}
+
+impl<D: Dataset> DataLoader<D> {
+    pub fn new(dataset: D, batch_size: usize, shuffle: bool) -> Self {
+        let mut loader = DataLoader {
+            dataset,
+            batch_size,
+            shuffle,
+            drop_last: false,
+            rng: Rng::new(0),
+            index: Vec::new(),
+            iteration: 0,
+        };
+        loader.reset();
+        loader
+    }
+
+    /// Reseeds the shuffling generator and starts a fresh epoch.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self.reset();
+        self
+    }
+
+    /// Skips the final batch of an epoch when it is smaller than `batch_size`.
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    pub fn max_iter(&self) -> usize {
+        let n = self.dataset.len();
+        if self.drop_last {
+            n / self.batch_size
+        } else {
+            n.div_ceil(self.batch_size)
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.iteration = 0;
+        self.index = if self.shuffle {
+            self.rng.permutation(self.dataset.len())
+        } else {
+            (0..self.dataset.len()).collect()
+        };
+    }
+}
+
+pub(crate) fn stack(items: &[ArrayD<f64>]) -> ArrayD<f64> {
+    let views: Vec<ArrayViewD<f64>> = items.iter().map(|a| a.view()).collect();
+    ndarray::stack(Axis(0), &views).expect("samples in a batch must share a shape")
+}
+
+impl<D: Dataset> Iterator for DataLoader<D> {
+    type Item = (Rc<RefCell<Variable>>, Rc<RefCell<Variable>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.iteration >= self.max_iter() {
+            self.reset();
+            return None;
+        }
+        let start = self.iteration * self.batch_size;
+        let end = (start + self.batch_size).min(self.index.len());
+        let (xs, ts): (Vec<_>, Vec<_>) = self.index[start..end]
+            .iter()
+            .map(|&i| self.dataset.get(i))
+            .unzip();
+        self.iteration += 1;
+        Some((Variable::new(stack(&xs)), Variable::new(stack(&ts))))
+    }
+}
+
+/// Loader for recurrent models: the dataset is read as one long sequence
+/// cut into `batch_size` parallel streams `len / batch_size` apart, and each
+/// batch holds the next time step of every stream. Order is never shuffled,
+/// so hidden state carries over correctly from one batch to the next.
+///
+/// Like DeZero's `SeqDataLoader`, an epoch lasts `ceil(len / batch_size)`
+/// batches and indices wrap around at the end of the dataset.
+pub struct SeqDataLoader<D: Dataset> {
+    pub dataset: D,
+    pub batch_size: usize,
+    pub iteration: usize,
+}
+
+impl<D: Dataset> SeqDataLoader<D> {
+    pub fn new(dataset: D, batch_size: usize) -> Self {
+        SeqDataLoader {
+            dataset,
+            batch_size,
+            iteration: 0,
+        }
+    }
+
+    pub fn max_iter(&self) -> usize {
+        self.dataset.len().div_ceil(self.batch_size)
+    }
+
+    pub fn reset(&mut self) {
+        self.iteration = 0;
+    }
+}
+
+impl<D: Dataset> Iterator for SeqDataLoader<D> {
+    type Item = (Rc<RefCell<Variable>>, Rc<RefCell<Variable>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.iteration >= self.max_iter() {
+            self.reset();
+            return None;
+        }
+        let n = self.dataset.len();
+        let jump = n / self.batch_size;
+        let (xs, ts): (Vec<_>, Vec<_>) = (0..self.batch_size)
+            .map(|i| self.dataset.get((i * jump + self.iteration) % n))
+            .unzip();
+        self.iteration += 1;
+        Some((Variable::new(stack(&xs)), Variable::new(stack(&ts))))
+    }
+}
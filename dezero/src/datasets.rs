@@ -0,0 +1,427 @@
+use flate2::read::GzDecoder;
+use ndarray::{s, Array1, Array2, ArrayD, Axis, IxDyn};
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::random::Rng;
+
+pub type Transform = Box<dyn Fn(ArrayD<f64>) -> ArrayD<f64>>;
+
+/// Indexable collection of `(x, t)` samples. Class labels are stored as
+/// integral `f64`s so they batch like any other array.
+pub trait Dataset {
+    fn len(&self) -> usize;
+
+    fn get(&self, index: usize) -> (ArrayD<f64>, ArrayD<f64>);
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wraps the dataset so `transform` is applied to each `x` on access.
+    fn with_transform(self, transform: Transform) -> Transformed<Self>
+    where
+        Self: Sized,
+    {
+        Transformed {
+            dataset: self,
+            transform: Some(transform),
+            target_transform: None,
+        }
+    }
+}
+
+pub struct Transformed<D> {
+    pub dataset: D,
+    pub transform: Option<Transform>,
+    pub target_transform: Option<Transform>,
+}
+
+impl<D> Transformed<D> {
+    pub fn with_target_transform(mut self, target_transform: Transform) -> Self {
+        self.target_transform = Some(target_transform);
+        self
+    }
+}
+
+impl<D: Dataset> Dataset for Transformed<D> {
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> (ArrayD<f64>, ArrayD<f64>) {
+        let (x, t) = self.dataset.get(index);
+        let x = match &self.transform {
+            Some(f) => f(x),
+            None => x,
+        };
+        let t = match &self.target_transform {
+            Some(f) => f(t),
+            None => t,
+        };
+        (x, t)
+    }
+}
+
+/// In-memory dataset whose samples are the slices of `data` and `label`
+/// along the first axis.
+pub struct ArrayDataset {
+    pub data: ArrayD<f64>,
+    pub label: ArrayD<f64>,
+}
+
+impl ArrayDataset {
+    pub fn new(data: ArrayD<f64>, label: ArrayD<f64>) -> Self {
+        assert_eq!(
+            data.shape()[0],
+            label.shape()[0],
+            "data and label differ in sample count"
+        );
+        ArrayDataset { data, label }
+    }
+}
+
+impl Dataset for ArrayDataset {
+    fn len(&self) -> usize {
+        self.data.shape()[0]
+    }
+
+    fn get(&self, index: usize) -> (ArrayD<f64>, ArrayD<f64>) {
+        (
+            self.data.index_axis(Axis(0), index).to_owned(),
+            self.label.index_axis(Axis(0), index).to_owned(),
+        )
+    }
+}
+
+/// Reads an IDX file of unsigned bytes (the MNIST format), transparently
+/// decompressing it when it starts with the gzip magic. Returns the shape
+/// from the header and the raw values.
+pub fn read_idx(path: &Path) -> io::Result<(Vec<usize>, Vec<u8>)> {
+    let mut raw = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut raw)?;
+    let bytes = if raw.starts_with(&[0x1f, 0x8b]) {
+        let mut out = Vec::new();
+        GzDecoder::new(&raw[..]).read_to_end(&mut out)?;
+        out
+    } else {
+        raw
+    };
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if bytes.len() < 4 || bytes[0] != 0 || bytes[1] != 0 {
+        return Err(invalid(format!("{}: not an IDX file", path.display())));
+    }
+    if bytes[2] != 0x08 {
+        return Err(invalid(format!(
+            "{}: unsupported IDX element type 0x{:02x}, expected unsigned byte",
+            path.display(),
+            bytes[2]
+        )));
+    }
+    let ndim = bytes[3] as usize;
+    let header = 4 + 4 * ndim;
+    if bytes.len() < header {
+        return Err(invalid(format!("{}: truncated IDX header", path.display())));
+    }
+    let shape: Vec<usize> = bytes[4..header]
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as usize)
+        .collect();
+    let count = shape
+        .iter()
+        .try_fold(1usize, |n, &d| n.checked_mul(d))
+        .ok_or_else(|| {
+            invalid(format!(
+                "{}: IDX shape {:?} overflows",
+                path.display(),
+                shape
+            ))
+        })?;
+    if bytes.len() - header != count {
+        return Err(invalid(format!(
+            "{}: header declares {} values but file holds {}",
+            path.display(),
+            count,
+            bytes.len() - header
+        )));
+    }
+    Ok((shape, bytes[header..].to_vec()))
+}
+
+/// Finds `name` or `name.gz` in `dir`.
+fn locate(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let plain = dir.join(name);
+    if plain.is_file() {
+        return Ok(plain);
+    }
+    let gz = dir.join(format!("{}.gz", name));
+    if gz.is_file() {
+        return Ok(gz);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("neither {} nor {} exists", plain.display(), gz.display()),
+    ))
+}
+
+/// MNIST read from the four standard IDX files in a local directory; it
+/// never downloads anything. Fashion-MNIST ships under the same file names,
+/// so pointing this at its directory loads it as well.
+///
+/// Images are `[1, 28, 28]` with raw pixel values in `0..=255`; labels are
+/// class indices.
+pub struct MNIST {
+    pub images: ArrayD<u8>,
+    pub labels: Vec<u8>,
+}
+
+pub type FashionMNIST = MNIST;
+
+impl MNIST {
+    pub fn new(dir: impl AsRef<Path>, train: bool) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let prefix = if train { "train" } else { "t10k" };
+        let (shape, pixels) = read_idx(&locate(dir, &format!("{}-images-idx3-ubyte", prefix))?)?;
+        let (lshape, labels) = read_idx(&locate(dir, &format!("{}-labels-idx1-ubyte", prefix))?)?;
+        if shape.len() != 3 || lshape.len() != 1 || shape[0] != lshape[0] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("mismatched image {:?} and label {:?} shapes", shape, lshape),
+            ));
+        }
+        let images = ArrayD::from_shape_vec(IxDyn(&[shape[0], 1, shape[1], shape[2]]), pixels)
+            .expect("shape checked against header");
+        Ok(MNIST { images, labels })
+    }
+
+    pub fn label(&self, index: usize) -> usize {
+        self.labels[index] as usize
+    }
+}
+
+impl Dataset for MNIST {
+    fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn get(&self, index: usize) -> (ArrayD<f64>, ArrayD<f64>) {
+        let x = self.images.index_axis(Axis(0), index).mapv(f64::from);
+        let t = ndarray::arr0(self.labels[index] as f64).into_dyn();
+        (x, t)
+    }
+}
+
+fn shuffled(x: ArrayD<f64>, t: ArrayD<f64>, rng: &mut Rng) -> (ArrayD<f64>, ArrayD<f64>) {
+    let index = rng.permutation(t.len());
+    (x.select(Axis(0), &index), t.select(Axis(0), &index))
+}
+
+/// The three-armed spiral from the book: `num_class * num_data` points in
+/// 2-D with integer class labels, shuffled.
+pub fn make_spiral(num_data: usize, num_class: usize, seed: u64) -> (ArrayD<f64>, ArrayD<f64>) {
+    let mut rng = Rng::new(seed);
+    let size = num_class * num_data;
+    let mut x = Array2::<f64>::zeros((size, 2));
+    let mut t = Array1::<f64>::zeros(size);
+    for j in 0..num_class {
+        for i in 0..num_data {
+            let rate = i as f64 / num_data as f64;
+            let radius = rate;
+            let theta = j as f64 * 4.0 + 4.0 * rate + rng.normal() * 0.2;
+            let ix = num_data * j + i;
+            x[[ix, 0]] = radius * theta.sin();
+            x[[ix, 1]] = radius * theta.cos();
+            t[ix] = j as f64;
+        }
+    }
+    shuffled(x.into_dyn(), t.into_dyn(), &mut rng)
+}
+
+/// Spiral with the book's sizes (100 points, 3 classes) and a fixed seed
+/// for each split.
+pub fn get_spiral(train: bool) -> (ArrayD<f64>, ArrayD<f64>) {
+    make_spiral(100, 3, if train { 1984 } else { 2020 })
+}
+
+/// One period of a sine wave sampled at `num_data` points, as next-step
+/// prediction pairs: `data[i] = y[i]`, `label[i] = y[i + 1]`, both `[N, 1]`.
+/// The training split adds uniform noise in `[-0.05, 0.05)`; the test split
+/// is a clean cosine.
+pub fn make_sin_curve(num_data: usize, train: bool, seed: u64) -> (ArrayD<f64>, ArrayD<f64>) {
+    let mut rng = Rng::new(seed);
+    let x = Array1::linspace(0.0, 2.0 * std::f64::consts::PI, num_data);
+    let y = if train {
+        x.mapv(|v| v.sin() + (rng.uniform() - 0.5) * 0.1)
+    } else {
+        x.mapv(f64::cos)
+    };
+    let data = y.slice(s![..-1]).to_owned().insert_axis(Axis(1));
+    let label = y.slice(s![1..]).to_owned().insert_axis(Axis(1));
+    (data.into_dyn(), label.into_dyn())
+}
+
+pub fn get_sin_curve(train: bool) -> (ArrayD<f64>, ArrayD<f64>) {
+    make_sin_curve(1000, train, 0)
+}
+
+/// Two interleaving half circles with Gaussian `noise`, labelled 0 and 1.
+pub fn make_moons(n_samples: usize, noise: f64, seed: u64) -> (ArrayD<f64>, ArrayD<f64>) {
+    let mut rng = Rng::new(seed);
+    let n_out = n_samples / 2;
+    let n_in = n_samples - n_out;
+    let mut x = Array2::<f64>::zeros((n_samples, 2));
+    let mut t = Array1::<f64>::zeros(n_samples);
+    let angle = |i: usize, n: usize| {
+        if n > 1 {
+            std::f64::consts::PI * i as f64 / (n - 1) as f64
+        } else {
+            0.0
+        }
+    };
+    for i in 0..n_out {
+        let a = angle(i, n_out);
+        x[[i, 0]] = a.cos();
+        x[[i, 1]] = a.sin();
+    }
+    for i in 0..n_in {
+        let a = angle(i, n_in);
+        x[[n_out + i, 0]] = 1.0 - a.cos();
+        x[[n_out + i, 1]] = 1.0 - a.sin() - 0.5;
+        t[n_out + i] = 1.0;
+    }
+    x.mapv_inplace(|v| v + noise * rng.normal());
+    shuffled(x.into_dyn(), t.into_dyn(), &mut rng)
+}
+
+/// Isotropic Gaussian clusters, one per row of `centers` (shape
+/// `[num_class, dim]`), with samples split as evenly as possible.
+pub fn make_blobs(
+    n_samples: usize,
+    centers: &Array2<f64>,
+    cluster_std: f64,
+    seed: u64,
+) -> (ArrayD<f64>, ArrayD<f64>) {
+    let mut rng = Rng::new(seed);
+    let (num_class, dim) = centers.dim();
+    let mut x = Array2::<f64>::zeros((n_samples, dim));
+    let mut t = Array1::<f64>::zeros(n_samples);
+    let mut ix = 0;
+    for c in 0..num_class {
+        let count = n_samples / num_class + usize::from(c < n_samples % num_class);
+        for _ in 0..count {
+            for d in 0..dim {
+                x[[ix, d]] = centers[[c, d]] + cluster_std * rng.normal();
+            }
+            t[ix] = c as f64;
+            ix += 1;
+        }
+    }
+    shuffled(x.into_dyn(), t.into_dyn(), &mut rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dezero-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn idx_bytes(shape: &[u32], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0x08, shape.len() as u8];
+        for d in shape {
+            bytes.extend_from_slice(&d.to_be_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn write_idx(path: &Path, shape: &[u32], data: &[u8], gzip: bool) {
+        let bytes = idx_bytes(shape, data);
+        if gzip {
+            let mut enc = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+            enc.write_all(&bytes).unwrap();
+            enc.finish().unwrap();
+        } else {
+            fs::write(path, bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn mnist_reads_plain_and_gzipped_idx() {
+        let dir = temp_dir("mnist");
+        let pixels: Vec<u8> = (0..3 * 2 * 4).collect();
+        write_idx(
+            &dir.join("train-images-idx3-ubyte"),
+            &[3, 2, 4],
+            &pixels,
+            false,
+        );
+        write_idx(
+            &dir.join("train-labels-idx1-ubyte"),
+            &[3],
+            &[7, 0, 9],
+            false,
+        );
+        write_idx(
+            &dir.join("t10k-images-idx3-ubyte.gz"),
+            &[1, 2, 4],
+            &pixels[..8],
+            true,
+        );
+        write_idx(&dir.join("t10k-labels-idx1-ubyte.gz"), &[1], &[4], true);
+
+        let train = MNIST::new(&dir, true).unwrap();
+        assert_eq!(train.images.shape(), &[3, 1, 2, 4]);
+        assert_eq!(train.labels, vec![7, 0, 9]);
+        let (x, t) = train.get(1);
+        assert_eq!(x.shape(), &[1, 2, 4]);
+        assert_eq!(x[[0, 1, 3]], 15.0);
+        assert_eq!(t[[]], 0.0);
+
+        let test = MNIST::new(&dir, false).unwrap();
+        assert_eq!(test.images.shape(), &[1, 1, 2, 4]);
+        assert_eq!(test.label(0), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn idx_size_mismatch_is_invalid_data() {
+        let dir = temp_dir("idx_mismatch");
+        let short = dir.join("short");
+        write_idx(&short, &[2, 3], &[1, 2, 3, 4, 5], false);
+        let err = read_idx(&short).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let overflow = dir.join("overflow");
+        write_idx(&overflow, &[u32::MAX, u32::MAX, u32::MAX], &[], false);
+        assert_eq!(
+            read_idx(&overflow).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        write_idx(
+            &dir.join("train-images-idx3-ubyte"),
+            &[2, 1, 1],
+            &[1, 2],
+            false,
+        );
+        write_idx(
+            &dir.join("train-labels-idx1-ubyte"),
+            &[3],
+            &[0, 1, 2],
+            false,
+        );
+        let err = MNIST::new(&dir, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
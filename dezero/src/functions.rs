@@ -0,0 +1,1572 @@
+use ndarray::{Array1, Array2, Array3, ArrayD, ArrayView2, ArrayViewD, Axis, Ix2, IxDyn};
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::core::{self, call1, Function, Variable};
+pub use crate::core::{add, div, mul, neg, pow, scalar, sub};
+pub use crate::functions_conv::{
+    adaptive_avg_pool2d, avg_pool2d, col2im, conv1d, conv2d, conv_transpose2d, global_avg_pool2d,
+    global_max_pool2d, im2col, max_pool2d, upsample, Conv1dOpts, ConvOpts, UpsampleMode,
+};
+use crate::random::{self, Rng};
+use crate::utils;
+
+pub struct Square;
+
+impl Function for Square {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| x.powi(2))]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![2.0 * xs[0] * &gys[0]]
+    }
+}
+
+pub fn square(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Square, &[x])
+}
+
+pub struct Exp;
+
+impl Function for Exp {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::exp)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::exp) * &gys[0]]
+    }
+}
+
+pub fn exp(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Exp, &[x])
+}
+
+pub struct Log;
+
+impl Function for Log {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::ln)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![&gys[0] / xs[0]]
+    }
+}
+
+pub fn log(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Log, &[x])
+}
+
+pub struct Sin;
+
+impl Function for Sin {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::sin)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::cos) * &gys[0]]
+    }
+}
+
+pub fn sin(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Sin, &[x])
+}
+
+pub struct Cos;
+
+impl Function for Cos {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::cos)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| -x.sin()) * &gys[0]]
+    }
+}
+
+pub fn cos(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Cos, &[x])
+}
+
+pub struct Tanh;
+
+impl Function for Tanh {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(f64::tanh)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| 1.0 - x.tanh().powi(2)) * &gys[0]]
+    }
+}
+
+pub fn tanh(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Tanh, &[x])
+}
+
+pub struct Reshape {
+    shape: Vec<usize>,
+}
+
+impl Function for Reshape {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::reshape(xs[0], &self.shape)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::reshape(&gys[0], xs[0].shape())]
+    }
+}
+
+pub fn reshape(x: &Rc<RefCell<Variable>>, shape: &[usize]) -> Rc<RefCell<Variable>> {
+    if x.borrow().shape() == shape {
+        return x.clone();
+    }
+    call1(
+        Reshape {
+            shape: shape.to_vec(),
+        },
+        &[x],
+    )
+}
+
+pub struct Transpose {
+    axes: Option<Vec<usize>>,
+}
+
+impl Function for Transpose {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let y = match &self.axes {
+            Some(axes) => xs[0].clone().permuted_axes(IxDyn(axes)),
+            None => xs[0].t().to_owned(),
+        };
+        vec![y.as_standard_layout().into_owned()]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gx = match &self.axes {
+            Some(axes) => {
+                let mut inv = vec![0; axes.len()];
+                for (i, &a) in axes.iter().enumerate() {
+                    inv[a] = i;
+                }
+                gys[0].clone().permuted_axes(IxDyn(&inv))
+            }
+            None => gys[0].t().to_owned(),
+        };
+        vec![gx.as_standard_layout().into_owned()]
+    }
+}
+
+/// Reverses the axes, like `x.T` in NumPy.
+pub fn transpose(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Transpose { axes: None }, &[x])
+}
+
+pub fn transpose_axes(x: &Rc<RefCell<Variable>>, axes: &[usize]) -> Rc<RefCell<Variable>> {
+    call1(
+        Transpose {
+            axes: Some(axes.to_vec()),
+        },
+        &[x],
+    )
+}
+
+pub struct Sum {
+    axis: Option<Vec<usize>>,
+    keepdims: bool,
+}
+
+impl Function for Sum {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let y = match &self.axis {
+            None => {
+                let s = xs[0].sum();
+                if self.keepdims {
+                    ArrayD::from_elem(IxDyn(&vec![1; xs[0].ndim()]), s)
+                } else {
+                    ndarray::arr0(s).into_dyn()
+                }
+            }
+            Some(axes) => {
+                let mut sorted = axes.clone();
+                sorted.sort_unstable_by(|a, b| b.cmp(a));
+                let mut y = xs[0].clone();
+                for &a in &sorted {
+                    y = y.sum_axis(Axis(a));
+                    if self.keepdims {
+                        y = y.insert_axis(Axis(a));
+                    }
+                }
+                y
+            }
+        };
+        vec![y]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gy = utils::reshape_sum_backward(&gys[0], xs[0].shape(), &self.axis, self.keepdims);
+        vec![utils::broadcast_to(&gy, xs[0].shape())]
+    }
+}
+
+pub fn sum(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(
+        Sum {
+            axis: None,
+            keepdims: false,
+        },
+        &[x],
+    )
+}
+
+pub fn sum_axis(
+    x: &Rc<RefCell<Variable>>,
+    axis: &[usize],
+    keepdims: bool,
+) -> Rc<RefCell<Variable>> {
+    call1(
+        Sum {
+            axis: Some(axis.to_vec()),
+            keepdims,
+        },
+        &[x],
+    )
+}
+
+pub struct SumTo {
+    shape: Vec<usize>,
+}
+
+impl Function for SumTo {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::sum_to(xs[0], &self.shape)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::broadcast_to(&gys[0], xs[0].shape())]
+    }
+}
+
+pub fn sum_to(x: &Rc<RefCell<Variable>>, shape: &[usize]) -> Rc<RefCell<Variable>> {
+    if x.borrow().shape() == shape {
+        return x.clone();
+    }
+    call1(
+        SumTo {
+            shape: shape.to_vec(),
+        },
+        &[x],
+    )
+}
+
+pub struct BroadcastTo {
+    shape: Vec<usize>,
+}
+
+impl Function for BroadcastTo {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::broadcast_to(xs[0], &self.shape)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![utils::sum_to(&gys[0], xs[0].shape())]
+    }
+}
+
+pub fn broadcast_to(x: &Rc<RefCell<Variable>>, shape: &[usize]) -> Rc<RefCell<Variable>> {
+    if x.borrow().shape() == shape {
+        return x.clone();
+    }
+    call1(
+        BroadcastTo {
+            shape: shape.to_vec(),
+        },
+        &[x],
+    )
+}
+
+/// `x[.., start..end, ..]` along `axis`; backward scatters the gradient
+/// back into zeros of the input shape.
+pub struct SliceAxis {
+    axis: usize,
+    range: Range<usize>,
+}
+
+impl Function for SliceAxis {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let slice = xs[0].slice_axis(Axis(self.axis), self.range.clone().into());
+        vec![slice.to_owned()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let mut gx = ArrayD::zeros(xs[0].raw_dim());
+        gx.slice_axis_mut(Axis(self.axis), self.range.clone().into())
+            .assign(&gys[0]);
+        vec![gx]
+    }
+}
+
+pub fn slice_axis(
+    x: &Rc<RefCell<Variable>>,
+    axis: usize,
+    range: Range<usize>,
+) -> Rc<RefCell<Variable>> {
+    call1(SliceAxis { axis, range }, &[x])
+}
+
+pub struct MatMul;
+
+fn dot2(a: ArrayViewD<f64>, b: ArrayViewD<f64>) -> ArrayD<f64> {
+    let a = a
+        .into_dimensionality::<Ix2>()
+        .expect("matmul expects 2-D arrays");
+    let b = b
+        .into_dimensionality::<Ix2>()
+        .expect("matmul expects 2-D arrays");
+    a.dot(&b).into_dyn()
+}
+
+/// Matrix product over the last two axes, with any leading axes treated as
+/// batch axes. `b` either has the same batch axes as `a` or is a single 2-D
+/// matrix shared by every batch entry.
+fn batch_dot(a: ArrayViewD<f64>, b: ArrayViewD<f64>) -> ArrayD<f64> {
+    if a.ndim() == 2 && b.ndim() == 2 {
+        return dot2(a, b);
+    }
+    assert!(
+        a.ndim() >= 2 && b.ndim() >= 2,
+        "matmul expects arrays of at least 2 dimensions"
+    );
+    let (m, k) = (a.shape()[a.ndim() - 2], a.shape()[a.ndim() - 1]);
+    let n = b.shape()[b.ndim() - 1];
+    let batch = &a.shape()[..a.ndim() - 2];
+    let mut shape = batch.to_vec();
+    shape.extend([m, n]);
+    if b.ndim() == 2 {
+        let a = a.to_shape((a.len() / k, k)).unwrap();
+        let b = b.into_dimensionality::<Ix2>().unwrap();
+        return a.dot(&b).into_shape(IxDyn(&shape)).unwrap();
+    }
+    assert_eq!(
+        batch,
+        &b.shape()[..b.ndim() - 2],
+        "matmul batch axes must match"
+    );
+    let nb = batch.iter().product();
+    let a = a.to_shape((nb, m, k)).unwrap();
+    let b = b.to_shape((nb, b.shape()[b.ndim() - 2], n)).unwrap();
+    let mut y = Array3::zeros((nb, m, n));
+    for (mut y, (a, b)) in y.outer_iter_mut().zip(a.outer_iter().zip(b.outer_iter())) {
+        y.assign(&a.dot(&b));
+    }
+    y.into_shape(IxDyn(&shape)).unwrap()
+}
+
+/// The last two axes swapped, i.e. the transpose of every matrix in a batch.
+fn swap_last(x: &ArrayD<f64>) -> ArrayViewD<'_, f64> {
+    let n = x.ndim();
+    let mut v = x.view();
+    v.swap_axes(n - 2, n - 1);
+    v
+}
+
+/// Gradients of `batch_dot(x, w)`. A shared 2-D `w` collects the gradient
+/// of every batch entry.
+fn batch_dot_backward(
+    x: &ArrayD<f64>,
+    w: &ArrayD<f64>,
+    gy: &ArrayD<f64>,
+) -> (ArrayD<f64>, ArrayD<f64>) {
+    let gx = batch_dot(gy.view(), swap_last(w));
+    let gw = if w.ndim() == 2 && x.ndim() > 2 {
+        let k = x.shape()[x.ndim() - 1];
+        let n = gy.shape()[gy.ndim() - 1];
+        let x = x.to_shape((x.len() / k, k)).unwrap();
+        let gy = gy.to_shape((gy.len() / n, n)).unwrap();
+        x.t().dot(&gy).into_dyn()
+    } else {
+        batch_dot(swap_last(x), gy.view())
+    };
+    (gx, gw)
+}
+
+impl Function for MatMul {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![batch_dot(xs[0].view(), xs[1].view())]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (gx, gw) = batch_dot_backward(xs[0], xs[1], &gys[0]);
+        vec![gx, gw]
+    }
+}
+
+/// Matrix product over the last two axes. Inputs with more than two axes
+/// are batches of matrices: `w` must share their leading axes or be a
+/// single 2-D matrix applied to each of them.
+pub fn matmul(x: &Rc<RefCell<Variable>>, w: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(MatMul, &[x, w])
+}
+
+pub struct Sigmoid;
+
+impl Function for Sigmoid {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(sigmoid_scalar)]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let y = xs[0].mapv(sigmoid_scalar);
+        vec![&gys[0] * &(&y * &(1.0 - &y))]
+    }
+}
+
+pub fn sigmoid(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Sigmoid, &[x])
+}
+
+pub struct Linear;
+
+impl Function for Linear {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let y = batch_dot(xs[0].view(), xs[1].view());
+        match xs.get(2) {
+            Some(b) => vec![y + *b],
+            None => vec![y],
+        }
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (gx, gw) = batch_dot_backward(xs[0], xs[1], &gys[0]);
+        let mut gxs = vec![gx, gw];
+        if let Some(b) = xs.get(2) {
+            gxs.push(utils::sum_to(&gys[0], b.shape()));
+        }
+        gxs
+    }
+}
+
+/// `x W + b` fused into a single graph node. `x` may have extra leading
+/// axes, e.g. `[N, T, in]`; `W` applies to the last one.
+pub fn linear(
+    x: &Rc<RefCell<Variable>>,
+    w: &Rc<RefCell<Variable>>,
+    b: Option<&Rc<RefCell<Variable>>>,
+) -> Rc<RefCell<Variable>> {
+    match b {
+        Some(b) => call1(Linear, &[x, w, b]),
+        None => call1(Linear, &[x, w]),
+    }
+}
+
+/// Row lookup `W[ids]`. `W` is held by the node rather than passed as an
+/// input: backward appends the output gradient to `W.sparse_grad` for the
+/// looked-up rows only, so no vocabulary-sized dense gradient is built.
+pub struct EmbedId {
+    w: Rc<RefCell<Variable>>,
+    ids: Vec<usize>,
+}
+
+impl Function for EmbedId {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let w = self.w.borrow();
+        let vocab = w.shape()[0];
+        self.ids = xs[0]
+            .iter()
+            .map(|&v| {
+                assert!(
+                    v >= 0.0 && v.fract() == 0.0 && (v as usize) < vocab,
+                    "embedding ids must be integers in 0..{}, got {}",
+                    vocab,
+                    v
+                );
+                v as usize
+            })
+            .collect();
+        let mut shape = xs[0].shape().to_vec();
+        shape.extend_from_slice(&w.shape()[1..]);
+        let y = w.data.select(Axis(0), &self.ids);
+        vec![y.into_shape(IxDyn(&shape)).unwrap()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let mut w = self.w.borrow_mut();
+        let width = w.size() / w.shape()[0];
+        let gy = Array2::from_shape_vec((self.ids.len(), width), gys[0].iter().copied().collect())
+            .unwrap();
+        match w.sparse_grad.as_mut() {
+            Some(g) => g.push(&self.ids, &gy),
+            None => {
+                w.sparse_grad = Some(core::SparseGrad {
+                    rows: self.ids.clone(),
+                    values: gy,
+                })
+            }
+        }
+        vec![ArrayD::zeros(xs[0].raw_dim())]
+    }
+}
+
+/// Looks up rows of `w` (`[vocab, ...]`) by the integer ids in `ids`; the
+/// result has shape `ids.shape + w.shape[1..]`. `w` receives a row-sparse
+/// gradient in `sparse_grad` instead of `grad`, and optimizers update only
+/// the rows that were looked up.
+pub fn embed_id(ids: &Rc<RefCell<Variable>>, w: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    let f = EmbedId {
+        w: w.clone(),
+        ids: Vec::new(),
+    };
+    call1(f, &[ids])
+}
+
+/// Gradient is 1 for `x > 0` and 0 otherwise, including at `x == 0`.
+pub struct ReLU;
+
+impl Function for ReLU {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| x.max(0.0))]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let mask = xs[0].mapv(|x| if x > 0.0 { 1.0 } else { 0.0 });
+        vec![&gys[0] * &mask]
+    }
+}
+
+pub fn relu(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(ReLU, &[x])
+}
+
+/// Gradient is 1 for `x > 0` and `slope` otherwise, so `slope` at `x == 0`.
+pub struct LeakyReLU {
+    slope: f64,
+}
+
+impl Function for LeakyReLU {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let slope = self.slope;
+        vec![xs[0].mapv(|x| if x > 0.0 { x } else { slope * x })]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let slope = self.slope;
+        let mask = xs[0].mapv(|x| if x > 0.0 { 1.0 } else { slope });
+        vec![&gys[0] * &mask]
+    }
+}
+
+pub fn leaky_relu(x: &Rc<RefCell<Variable>>, slope: f64) -> Rc<RefCell<Variable>> {
+    call1(LeakyReLU { slope }, &[x])
+}
+
+/// `x` for `x > 0`, `alpha * (exp(x) - 1)` otherwise. The gradient at `x == 0`
+/// takes the left branch, `alpha`, which matches the right one when `alpha == 1`.
+pub struct ELU {
+    alpha: f64,
+}
+
+impl Function for ELU {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let alpha = self.alpha;
+        vec![xs[0].mapv(|x| if x > 0.0 { x } else { alpha * x.exp_m1() })]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let alpha = self.alpha;
+        let d = xs[0].mapv(|x| if x > 0.0 { 1.0 } else { alpha * x.exp() });
+        vec![&gys[0] * &d]
+    }
+}
+
+pub fn elu(x: &Rc<RefCell<Variable>>, alpha: f64) -> Rc<RefCell<Variable>> {
+    call1(ELU { alpha }, &[x])
+}
+
+const GELU_C: f64 = 0.797_884_560_802_865_4; // sqrt(2 / pi)
+
+/// GELU in its tanh approximation,
+/// `0.5 x (1 + tanh(sqrt(2/pi) (x + 0.044715 x^3)))`. Smooth everywhere;
+/// the backward pass is the exact derivative of this approximation.
+pub struct GELU;
+
+impl Function for GELU {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| 0.5 * x * (1.0 + (GELU_C * (x + 0.044715 * x.powi(3))).tanh()))]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let d = xs[0].mapv(|x| {
+            let t = (GELU_C * (x + 0.044715 * x.powi(3))).tanh();
+            let dt = (1.0 - t * t) * GELU_C * (1.0 + 3.0 * 0.044715 * x * x);
+            0.5 * (1.0 + t) + 0.5 * x * dt
+        });
+        vec![&gys[0] * &d]
+    }
+}
+
+pub fn gelu(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(GELU, &[x])
+}
+
+fn sigmoid_scalar(x: f64) -> f64 {
+    0.5 * (0.5 * x).tanh() + 0.5
+}
+
+/// SiLU (swish), `x * sigmoid(x)`. Smooth everywhere.
+pub struct SiLU;
+
+impl Function for SiLU {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| x * sigmoid_scalar(x))]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let d = xs[0].mapv(|x| {
+            let s = sigmoid_scalar(x);
+            s * (1.0 + x * (1.0 - s))
+        });
+        vec![&gys[0] * &d]
+    }
+}
+
+pub fn silu(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(SiLU, &[x])
+}
+
+/// `log(1 + exp(x))`, computed as `max(x, 0) + log1p(exp(-|x|))` so large
+/// inputs do not overflow. Its gradient is `sigmoid(x)`.
+pub struct Softplus;
+
+impl Function for Softplus {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0].mapv(|x| x.max(0.0) + (-x.abs()).exp().ln_1p())]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![&gys[0] * &xs[0].mapv(sigmoid_scalar)]
+    }
+}
+
+pub fn softplus(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    call1(Softplus, &[x])
+}
+
+/// Multiplies by a fixed mask of `0` or `1 / (1 - ratio)`.
+pub struct Dropout {
+    mask: ArrayD<f64>,
+}
+
+impl Function for Dropout {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![xs[0] * &self.mask]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![&gys[0] * &self.mask]
+    }
+}
+
+/// Inverted dropout drawing the mask from `rng`. Zeroes each element with
+/// probability `ratio` and scales the rest so the expectation is unchanged;
+/// in test mode `x` is returned as is.
+pub fn dropout_with(x: &Rc<RefCell<Variable>>, ratio: f64, rng: &mut Rng) -> Rc<RefCell<Variable>> {
+    assert!(
+        (0.0..1.0).contains(&ratio),
+        "dropout ratio must be in [0, 1)"
+    );
+    if !core::is_training() {
+        return x.clone();
+    }
+    let scale = 1.0 / (1.0 - ratio);
+    let mask = rng
+        .rand(x.borrow().data.shape())
+        .mapv(|u| if u >= ratio { scale } else { 0.0 });
+    call1(Dropout { mask }, &[x])
+}
+
+/// `dropout_with` using the global generator, so `random::seed` makes it
+/// reproducible.
+pub fn dropout(x: &Rc<RefCell<Variable>>, ratio: f64) -> Rc<RefCell<Variable>> {
+    random::with_rng(|rng| dropout_with(x, ratio, rng))
+}
+
+/// Standardizes each row to zero mean and unit variance, returning the
+/// normalized rows and the per-row `1 / sqrt(var + eps)`.
+fn normalize_rows(x: ArrayView2<f64>, eps: f64) -> (Array2<f64>, Array1<f64>) {
+    let mean = x.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+    let xc = &x - &mean;
+    let var = xc.mapv(|v| v * v).mean_axis(Axis(1)).unwrap();
+    let inv_std = var.mapv(|v| 1.0 / (v + eps).sqrt());
+    let xhat = xc * inv_std.view().insert_axis(Axis(1));
+    (xhat, inv_std)
+}
+
+/// Fused backward of `normalize_rows`:
+/// `gx = inv_std * (g - mean(g) - xhat * mean(g * xhat))` per row.
+fn normalize_rows_backward(
+    gxhat: ArrayView2<f64>,
+    xhat: &Array2<f64>,
+    inv_std: &Array1<f64>,
+) -> Array2<f64> {
+    let mean_g = gxhat.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+    let mean_gx = (&gxhat * xhat)
+        .mean_axis(Axis(1))
+        .unwrap()
+        .insert_axis(Axis(1));
+    (&gxhat - &mean_g - xhat * &mean_gx) * inv_std.view().insert_axis(Axis(1))
+}
+
+/// `[N, C, ...]` as `[C, N * ...]`, one row per channel.
+fn channel_rows(x: &ArrayD<f64>) -> Array2<f64> {
+    let c = x.shape()[1];
+    let mut v = x.view();
+    v.swap_axes(0, 1);
+    let v = v.as_standard_layout().into_owned();
+    let n = v.len() / c;
+    v.into_shape((c, n)).unwrap()
+}
+
+fn from_channel_rows(rows: Array2<f64>, shape: &[usize]) -> ArrayD<f64> {
+    let mut swapped = shape.to_vec();
+    swapped.swap(0, 1);
+    let mut a = rows.into_shape(IxDyn(&swapped)).unwrap();
+    a.swap_axes(0, 1);
+    a.as_standard_layout().into_owned()
+}
+
+/// Reshapes a `[C]` vector to `[1, C, 1, ...]` for broadcasting against an
+/// `ndim`-dimensional `[N, C, ...]` array.
+fn per_channel(v: &ArrayD<f64>, ndim: usize) -> ArrayD<f64> {
+    let mut shape = vec![1; ndim];
+    shape[1] = v.len();
+    v.to_shape(IxDyn(&shape)).unwrap().into_owned()
+}
+
+/// `x` in C order as rows of `ncols` elements.
+fn to_rows(x: &ArrayD<f64>, ncols: usize) -> Array2<f64> {
+    let nrows = x.len() / ncols;
+    let x = x.as_standard_layout().into_owned();
+    x.into_shape((nrows, ncols)).unwrap()
+}
+
+fn as_column(v: &ArrayD<f64>) -> ArrayView2<'_, f64> {
+    v.view().into_shape((v.len(), 1)).unwrap()
+}
+
+/// Batch normalization over every axis but the channel axis 1. In train
+/// mode it uses batch statistics and folds them into the running ones; in
+/// test mode it uses the running statistics.
+pub struct BatchNorm {
+    avg_mean: Rc<RefCell<Variable>>,
+    avg_var: Rc<RefCell<Variable>>,
+    decay: f64,
+    eps: f64,
+    train: bool,
+    xhat: Array2<f64>,
+    inv_std: Array1<f64>,
+}
+
+impl Function for BatchNorm {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, gamma, beta) = (xs[0], xs[1], xs[2]);
+        let rows = channel_rows(x);
+        if self.train {
+            (self.xhat, self.inv_std) = normalize_rows(rows.view(), self.eps);
+            let m = rows.ncols() as f64;
+            let adjust = if m > 1.0 { m / (m - 1.0) } else { 1.0 };
+            let mean = rows.mean_axis(Axis(1)).unwrap().into_dyn();
+            let var = rows.var_axis(Axis(1), 0.0).into_dyn() * adjust;
+            let decay = self.decay;
+            let mut avg_mean = self.avg_mean.borrow_mut();
+            avg_mean.data = &avg_mean.data * decay + mean * (1.0 - decay);
+            let mut avg_var = self.avg_var.borrow_mut();
+            avg_var.data = &avg_var.data * decay + var * (1.0 - decay);
+        } else {
+            let eps = self.eps;
+            let mean = self.avg_mean.borrow().data.clone();
+            self.inv_std = self
+                .avg_var
+                .borrow()
+                .data
+                .iter()
+                .map(|&v| 1.0 / (v + eps).sqrt())
+                .collect();
+            self.xhat = (&rows - &as_column(&mean)) * self.inv_std.view().insert_axis(Axis(1));
+        }
+        let y = &self.xhat * &as_column(gamma) + as_column(beta);
+        vec![from_channel_rows(y, x.shape())]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gy = channel_rows(&gys[0]);
+        let gbeta = gy.sum_axis(Axis(1));
+        let ggamma = (&gy * &self.xhat).sum_axis(Axis(1));
+        let gxhat = &gy * &as_column(xs[1]);
+        let gx = if self.train {
+            normalize_rows_backward(gxhat.view(), &self.xhat, &self.inv_std)
+        } else {
+            gxhat * self.inv_std.view().insert_axis(Axis(1))
+        };
+        vec![
+            from_channel_rows(gx, xs[0].shape()),
+            ggamma.into_dyn(),
+            gbeta.into_dyn(),
+        ]
+    }
+}
+
+/// `gamma * (x - mean) / sqrt(var + eps) + beta` with `[C]`-shaped `gamma`,
+/// `beta`, `avg_mean` and `avg_var` for `[N, C, ...]` input. The running
+/// statistics are updated in place in train mode as
+/// `avg = decay * avg + (1 - decay) * batch` (unbiased variance).
+pub fn batch_norm(
+    x: &Rc<RefCell<Variable>>,
+    gamma: &Rc<RefCell<Variable>>,
+    beta: &Rc<RefCell<Variable>>,
+    avg_mean: &Rc<RefCell<Variable>>,
+    avg_var: &Rc<RefCell<Variable>>,
+    decay: f64,
+    eps: f64,
+) -> Rc<RefCell<Variable>> {
+    let f = BatchNorm {
+        avg_mean: avg_mean.clone(),
+        avg_var: avg_var.clone(),
+        decay,
+        eps,
+        train: core::is_training(),
+        xhat: Array2::zeros((0, 0)),
+        inv_std: Array1::zeros(0),
+    };
+    call1(f, &[x, gamma, beta])
+}
+
+/// Normalizes over the trailing axes covered by `gamma`'s shape.
+pub struct LayerNorm {
+    eps: f64,
+    xhat: Array2<f64>,
+    inv_std: Array1<f64>,
+}
+
+impl Function for LayerNorm {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, gamma, beta) = (xs[0], xs[1], xs[2]);
+        let rows = to_rows(x, gamma.len());
+        (self.xhat, self.inv_std) = normalize_rows(rows.view(), self.eps);
+        let gamma = gamma.view().into_shape(gamma.len()).unwrap();
+        let beta = beta.view().into_shape(beta.len()).unwrap();
+        let y = &self.xhat * &gamma + beta;
+        vec![y.into_shape(x.shape()).unwrap()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gamma = xs[1];
+        let gy = to_rows(&gys[0], gamma.len());
+        let gbeta = gy.sum_axis(Axis(0));
+        let ggamma = (&gy * &self.xhat).sum_axis(Axis(0));
+        let gxhat = &gy * &gamma.view().into_shape(gamma.len()).unwrap();
+        let gx = normalize_rows_backward(gxhat.view(), &self.xhat, &self.inv_std);
+        vec![
+            gx.into_shape(xs[0].shape()).unwrap(),
+            ggamma.into_shape(gamma.shape()).unwrap(),
+            gbeta.into_shape(gamma.shape()).unwrap(),
+        ]
+    }
+}
+
+/// Layer normalization: each sample is standardized over the trailing axes
+/// matching `gamma`'s shape, then scaled by `gamma` and shifted by `beta`.
+pub fn layer_norm(
+    x: &Rc<RefCell<Variable>>,
+    gamma: &Rc<RefCell<Variable>>,
+    beta: &Rc<RefCell<Variable>>,
+    eps: f64,
+) -> Rc<RefCell<Variable>> {
+    let f = LayerNorm {
+        eps,
+        xhat: Array2::zeros((0, 0)),
+        inv_std: Array1::zeros(0),
+    };
+    call1(f, &[x, gamma, beta])
+}
+
+/// Splits the channels of `[N, C, ...]` input into `groups` and normalizes
+/// each group of each sample; `gamma` and `beta` are per channel.
+pub struct GroupNorm {
+    groups: usize,
+    eps: f64,
+    xhat: Array2<f64>,
+    inv_std: Array1<f64>,
+}
+
+impl GroupNorm {
+    fn rows(&self, x: &ArrayD<f64>) -> Array2<f64> {
+        to_rows(x, x.len() / (x.shape()[0] * self.groups))
+    }
+}
+
+impl Function for GroupNorm {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, gamma, beta) = (xs[0], xs[1], xs[2]);
+        assert!(
+            x.shape()[1] % self.groups == 0,
+            "{} channels cannot be split into {} groups",
+            x.shape()[1],
+            self.groups
+        );
+        (self.xhat, self.inv_std) = normalize_rows(self.rows(x).view(), self.eps);
+        let xhat = self.xhat.view().into_shape(x.shape()).unwrap();
+        let y = &xhat * &per_channel(gamma, x.ndim()) + per_channel(beta, x.ndim());
+        vec![y]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, gamma) = (xs[0], xs[1]);
+        let gy = &gys[0];
+        let xhat = self.xhat.view().into_shape(x.shape()).unwrap();
+        let ggamma = channel_rows(&(gy * &xhat)).sum_axis(Axis(1));
+        let gbeta = channel_rows(gy).sum_axis(Axis(1));
+        let gxhat = self.rows(&(gy * &per_channel(gamma, x.ndim())));
+        let gx = normalize_rows_backward(gxhat.view(), &self.xhat, &self.inv_std);
+        vec![
+            gx.into_shape(x.shape()).unwrap(),
+            ggamma.into_dyn(),
+            gbeta.into_dyn(),
+        ]
+    }
+}
+
+pub fn group_norm(
+    x: &Rc<RefCell<Variable>>,
+    gamma: &Rc<RefCell<Variable>>,
+    beta: &Rc<RefCell<Variable>>,
+    groups: usize,
+    eps: f64,
+) -> Rc<RefCell<Variable>> {
+    let f = GroupNorm {
+        groups,
+        eps,
+        xhat: Array2::zeros((0, 0)),
+        inv_std: Array1::zeros(0),
+    };
+    call1(f, &[x, gamma, beta])
+}
+
+fn max_keepdims(x: &ArrayD<f64>, axis: usize) -> ArrayD<f64> {
+    x.fold_axis(Axis(axis), f64::NEG_INFINITY, |&m, &v| m.max(v))
+        .insert_axis(Axis(axis))
+}
+
+/// `x - logsumexp(x)` along `axis`, shifting by the row max first so large
+/// logits never reach `exp`.
+fn log_softmax_array(x: &ArrayD<f64>, axis: usize) -> ArrayD<f64> {
+    let m = max_keepdims(x, axis).mapv(|m| if m.is_finite() { m } else { 0.0 });
+    let y = x - &m;
+    let lse = y
+        .mapv(f64::exp)
+        .sum_axis(Axis(axis))
+        .insert_axis(Axis(axis))
+        .mapv(f64::ln);
+    y - &lse
+}
+
+fn softmax_array(x: &ArrayD<f64>, axis: usize) -> ArrayD<f64> {
+    log_softmax_array(x, axis).mapv(f64::exp)
+}
+
+pub struct Softmax {
+    axis: usize,
+    y: ArrayD<f64>,
+}
+
+impl Function for Softmax {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        self.y = softmax_array(xs[0], self.axis);
+        vec![self.y.clone()]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gx = &self.y * &gys[0];
+        let sumdx = gx.sum_axis(Axis(self.axis)).insert_axis(Axis(self.axis));
+        vec![&gx - &(&self.y * &sumdx)]
+    }
+}
+
+pub fn softmax(x: &Rc<RefCell<Variable>>, axis: usize) -> Rc<RefCell<Variable>> {
+    call1(
+        Softmax {
+            axis,
+            y: ArrayD::zeros(IxDyn(&[0])),
+        },
+        &[x],
+    )
+}
+
+pub struct LogSoftmax {
+    axis: usize,
+    y: ArrayD<f64>,
+}
+
+impl Function for LogSoftmax {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        self.y = log_softmax_array(xs[0], self.axis);
+        vec![self.y.clone()]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let sum_gy = gys[0]
+            .sum_axis(Axis(self.axis))
+            .insert_axis(Axis(self.axis));
+        vec![&gys[0] - &(self.y.mapv(f64::exp) * &sum_gy)]
+    }
+}
+
+pub fn log_softmax(x: &Rc<RefCell<Variable>>, axis: usize) -> Rc<RefCell<Variable>> {
+    call1(
+        LogSoftmax {
+            axis,
+            y: ArrayD::zeros(IxDyn(&[0])),
+        },
+        &[x],
+    )
+}
+
+/// `[t, t]` mask letting position `i` attend only to positions `0..=i`,
+/// for use with `scaled_dot_product_attention`.
+pub fn causal_mask(t: usize) -> ArrayD<f64> {
+    Array2::from_shape_fn((t, t), |(i, j)| if j <= i { 1.0 } else { 0.0 }).into_dyn()
+}
+
+/// The attention weights `softmax(q k^T / sqrt(d) + mask)` over the last
+/// axis, shaped `[..., Tq, Tk]`. See `scaled_dot_product_attention`.
+pub fn attention_weights(
+    q: &Rc<RefCell<Variable>>,
+    k: &Rc<RefCell<Variable>>,
+    mask: Option<&ArrayD<f64>>,
+) -> Rc<RefCell<Variable>> {
+    let ndim = k.borrow().ndim();
+    let d = k.borrow().shape()[ndim - 1] as f64;
+    let mut axes: Vec<usize> = (0..ndim).collect();
+    axes.swap(ndim - 2, ndim - 1);
+    let scores = matmul(q, &transpose_axes(k, &axes));
+    let mut scores = mul(&scores, &core::scalar(1.0 / d.sqrt()));
+    if let Some(mask) = mask {
+        let bias = mask.mapv(|m| if m != 0.0 { 0.0 } else { f64::NEG_INFINITY });
+        scores = add(&scores, &Variable::new(bias));
+    }
+    softmax(&scores, ndim - 1)
+}
+
+/// `softmax(q k^T / sqrt(d)) v` for `q` of shape `[..., Tq, d]`, `k` of
+/// `[..., Tk, d]` and `v` of `[..., Tk, dv]`. `mask` broadcasts against the
+/// `[..., Tq, Tk]` scores: a zero entry keeps that query from attending to
+/// that key. Every query needs at least one unmasked key, or its output is NaN.
+pub fn scaled_dot_product_attention(
+    q: &Rc<RefCell<Variable>>,
+    k: &Rc<RefCell<Variable>>,
+    v: &Rc<RefCell<Variable>>,
+    mask: Option<&ArrayD<f64>>,
+) -> Rc<RefCell<Variable>> {
+    matmul(&attention_weights(q, k, mask), v)
+}
+
+fn class_labels(t: &ArrayD<f64>, classes: usize) -> Vec<usize> {
+    t.iter()
+        .map(|&v| {
+            assert!(
+                v >= 0.0 && v.fract() == 0.0 && (v as usize) < classes,
+                "class labels must be integers in 0..{}, got {}",
+                classes,
+                v
+            );
+            v as usize
+        })
+        .collect()
+}
+
+/// Mean cross-entropy between softmax(`x`) over axis 1 and integer class
+/// labels. Forward and backward work from log-softmax, so no `exp` of a raw
+/// logit is ever taken.
+pub struct SoftmaxCrossEntropy;
+
+impl Function for SoftmaxCrossEntropy {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, t) = (xs[0], class_labels(xs[1], xs[0].shape()[1]));
+        let n = x.shape()[0];
+        let log_p = log_softmax_array(x, 1);
+        let log_p = log_p.view().into_dimensionality::<Ix2>().unwrap();
+        let loss: f64 = t.iter().enumerate().map(|(i, &c)| log_p[[i, c]]).sum();
+        vec![ndarray::arr0(-loss / n as f64).into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, t) = (xs[0], class_labels(xs[1], xs[0].shape()[1]));
+        let n = x.shape()[0];
+        let gy = gys[0].first().copied().unwrap_or(1.0) / n as f64;
+        let mut y = softmax_array(x, 1);
+        {
+            let mut y2 = y.view_mut().into_dimensionality::<Ix2>().unwrap();
+            for (i, &c) in t.iter().enumerate() {
+                y2[[i, c]] -= 1.0;
+            }
+        }
+        vec![y * gy, ArrayD::zeros(xs[1].raw_dim())]
+    }
+}
+
+pub fn softmax_cross_entropy(
+    x: &Rc<RefCell<Variable>>,
+    t: &Rc<RefCell<Variable>>,
+) -> Rc<RefCell<Variable>> {
+    call1(SoftmaxCrossEntropy, &[x, t])
+}
+
+/// Cross-entropy of sigmoid(`x`) against 0/1 targets, summed and divided by
+/// the batch size. Uses `max(x, 0) - x t + log(1 + exp(-|x|))`, which stays
+/// finite for any logit.
+pub struct SigmoidCrossEntropy;
+
+impl Function for SigmoidCrossEntropy {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, t) = (xs[0], &utils::reshape(xs[1], xs[0].shape()));
+        let n = x.shape().first().copied().unwrap_or(1);
+        let loss: f64 = x
+            .iter()
+            .zip(t.iter())
+            .map(|(&x, &t)| x.max(0.0) - x * t + (-x.abs()).exp().ln_1p())
+            .sum();
+        vec![ndarray::arr0(loss / n as f64).into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, t) = (xs[0], &utils::reshape(xs[1], xs[0].shape()));
+        let n = x.shape().first().copied().unwrap_or(1);
+        let gy = gys[0].first().copied().unwrap_or(1.0) / n as f64;
+        let gx = (x.mapv(sigmoid_scalar) - t) * gy;
+        vec![gx, ArrayD::zeros(xs[1].raw_dim())]
+    }
+}
+
+pub fn sigmoid_cross_entropy(
+    x: &Rc<RefCell<Variable>>,
+    t: &Rc<RefCell<Variable>>,
+) -> Rc<RefCell<Variable>> {
+    call1(SigmoidCrossEntropy, &[x, t])
+}
+
+/// Cross-entropy of probabilities `p` against 0/1 targets, summed and
+/// divided by the batch size. `p` is clipped to `[eps, 1 - eps]`; prefer
+/// `sigmoid_cross_entropy` on logits when possible.
+pub struct BinaryCrossEntropy {
+    eps: f64,
+}
+
+impl Function for BinaryCrossEntropy {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (p, t) = (xs[0], &utils::reshape(xs[1], xs[0].shape()));
+        let n = p.shape().first().copied().unwrap_or(1);
+        let eps = self.eps;
+        let loss: f64 = p
+            .iter()
+            .zip(t.iter())
+            .map(|(&p, &t)| {
+                let p = p.clamp(eps, 1.0 - eps);
+                -(t * p.ln() + (1.0 - t) * (1.0 - p).ln())
+            })
+            .sum();
+        vec![ndarray::arr0(loss / n as f64).into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (p, t) = (xs[0], &utils::reshape(xs[1], xs[0].shape()));
+        let n = p.shape().first().copied().unwrap_or(1);
+        let gy = gys[0].first().copied().unwrap_or(1.0) / n as f64;
+        let eps = self.eps;
+        let mut gx = p.clone();
+        ndarray::Zip::from(&mut gx).and(t).for_each(|g, &t| {
+            let p = g.clamp(eps, 1.0 - eps);
+            *g = (p - t) / (p * (1.0 - p)) * gy;
+        });
+        vec![gx, ArrayD::zeros(xs[1].raw_dim())]
+    }
+}
+
+pub fn binary_cross_entropy(
+    p: &Rc<RefCell<Variable>>,
+    t: &Rc<RefCell<Variable>>,
+) -> Rc<RefCell<Variable>> {
+    call1(BinaryCrossEntropy { eps: 1e-15 }, &[p, t])
+}
+
+/// Fraction of rows of `y` whose argmax equals the label in `t`. Not differentiable.
+pub fn accuracy(y: &Rc<RefCell<Variable>>, t: &Rc<RefCell<Variable>>) -> f64 {
+    let y = y.borrow();
+    let y = y
+        .data
+        .view()
+        .into_dimensionality::<Ix2>()
+        .expect("accuracy expects (N, C) scores");
+    let t = class_labels(&t.borrow().data, y.ncols());
+    let correct = y
+        .outer_iter()
+        .zip(t.iter())
+        .filter(|(row, &c)| {
+            let pred = row
+                .iter()
+                .enumerate()
+                .fold((0, f64::NEG_INFINITY), |best, (i, &v)| {
+                    if v > best.1 {
+                        (i, v)
+                    } else {
+                        best
+                    }
+                })
+                .0;
+            pred == c
+        })
+        .count();
+    correct as f64 / t.len() as f64
+}
+
+/// Mean over every element.
+pub fn mean(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    let n = x.borrow().size() as f64;
+    div(&sum(x), &scalar(n))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    Mean,
+    Sum,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegressionKind {
+    SquaredError,
+    AbsoluteError,
+    /// Quadratic within `delta` of the target, linear beyond it.
+    Huber(f64),
+    LogCosh,
+}
+
+impl RegressionKind {
+    fn value(self, d: f64) -> f64 {
+        match self {
+            RegressionKind::SquaredError => d * d,
+            RegressionKind::AbsoluteError => d.abs(),
+            RegressionKind::Huber(delta) => {
+                if d.abs() <= delta {
+                    0.5 * d * d
+                } else {
+                    delta * (d.abs() - 0.5 * delta)
+                }
+            }
+            // log(cosh(d)) = |d| + log(1 + exp(-2|d|)) - log(2), finite for large |d|.
+            RegressionKind::LogCosh => {
+                d.abs() + (-2.0 * d.abs()).exp().ln_1p() - std::f64::consts::LN_2
+            }
+        }
+    }
+
+    /// Derivative with respect to `d`. The absolute error takes 0 at `d == 0`;
+    /// Huber is differentiable everywhere.
+    fn grad(self, d: f64) -> f64 {
+        match self {
+            RegressionKind::SquaredError => 2.0 * d,
+            RegressionKind::AbsoluteError => {
+                if d == 0.0 {
+                    0.0
+                } else {
+                    d.signum()
+                }
+            }
+            RegressionKind::Huber(delta) => d.clamp(-delta, delta),
+            RegressionKind::LogCosh => d.tanh(),
+        }
+    }
+}
+
+/// Elementwise loss of `x0 - x1`, optionally scaled per sample (first axis)
+/// by `weights`, then reduced. `Mean` divides by the element count.
+pub struct RegressionLoss {
+    kind: RegressionKind,
+    reduction: Reduction,
+    weights: Option<ArrayD<f64>>,
+}
+
+impl RegressionLoss {
+    fn sample_weights(&self, shape: &[usize]) -> Option<ArrayD<f64>> {
+        self.weights.as_ref().map(|w| {
+            let mut wshape = vec![1; shape.len()];
+            wshape[0] = w.len();
+            utils::broadcast_to(&utils::reshape(w, &wshape), shape)
+        })
+    }
+}
+
+impl Function for RegressionLoss {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let diff = xs[0] - xs[1];
+        let kind = self.kind;
+        let mut loss = diff.mapv(|d| kind.value(d));
+        if let Some(w) = self.sample_weights(loss.shape()) {
+            loss = loss * w;
+        }
+        let y = match self.reduction {
+            Reduction::None => loss,
+            Reduction::Sum => ndarray::arr0(loss.sum()).into_dyn(),
+            Reduction::Mean => ndarray::arr0(loss.mean().unwrap_or(0.0)).into_dyn(),
+        };
+        vec![y]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let diff = xs[0] - xs[1];
+        let kind = self.kind;
+        let mut gx = diff.mapv(|d| kind.grad(d));
+        if let Some(w) = self.sample_weights(gx.shape()) {
+            gx = gx * w;
+        }
+        let gx = match self.reduction {
+            Reduction::None => gx * &gys[0],
+            Reduction::Sum => gx * gys[0].sum(),
+            Reduction::Mean => {
+                let n = gx.len() as f64;
+                gx * (gys[0].sum() / n)
+            }
+        };
+        vec![
+            utils::sum_to(&gx, xs[0].shape()),
+            utils::sum_to(&-gx, xs[1].shape()),
+        ]
+    }
+}
+
+pub fn regression_loss(
+    x0: &Rc<RefCell<Variable>>,
+    x1: &Rc<RefCell<Variable>>,
+    kind: RegressionKind,
+    reduction: Reduction,
+    weights: Option<&ArrayD<f64>>,
+) -> Rc<RefCell<Variable>> {
+    call1(
+        RegressionLoss {
+            kind,
+            reduction,
+            weights: weights.cloned(),
+        },
+        &[x0, x1],
+    )
+}
+
+pub fn mean_squared_error(
+    x0: &Rc<RefCell<Variable>>,
+    x1: &Rc<RefCell<Variable>>,
+) -> Rc<RefCell<Variable>> {
+    regression_loss(x0, x1, RegressionKind::SquaredError, Reduction::Mean, None)
+}
+
+pub fn mean_absolute_error(
+    x0: &Rc<RefCell<Variable>>,
+    x1: &Rc<RefCell<Variable>>,
+) -> Rc<RefCell<Variable>> {
+    regression_loss(x0, x1, RegressionKind::AbsoluteError, Reduction::Mean, None)
+}
+
+pub fn huber_loss(
+    x0: &Rc<RefCell<Variable>>,
+    x1: &Rc<RefCell<Variable>>,
+    delta: f64,
+) -> Rc<RefCell<Variable>> {
+    regression_loss(x0, x1, RegressionKind::Huber(delta), Reduction::Mean, None)
+}
+
+pub fn log_cosh(x0: &Rc<RefCell<Variable>>, x1: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    regression_loss(x0, x1, RegressionKind::LogCosh, Reduction::Mean, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::VariableExt;
+    use crate::utils::gradient_check;
+    use crate::utils::testing::{randn, weighted, Var};
+    use ndarray::{arr1, arr2};
+
+    /// Gradient of `f` at a single zero input.
+    fn grad_at_zero(f: impl Fn(&Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>>) -> f64 {
+        let x = Variable::new(arr1(&[0.0]));
+        f(&x).backward();
+        x.grad().unwrap()[0]
+    }
+
+    #[test]
+    fn non_smooth_activations_at_zero() {
+        assert_eq!(grad_at_zero(relu), 0.0);
+        assert_eq!(grad_at_zero(|x| leaky_relu(x, 0.2)), 0.2);
+        assert_eq!(grad_at_zero(|x| elu(x, 0.5)), 0.5);
+    }
+
+    #[test]
+    fn smooth_activation_gradients() {
+        let x = randn(&[3, 4], 0);
+        assert!(gradient_check(&gelu, &x, 1e-5, 1e-8));
+        assert!(gradient_check(&silu, &x, 1e-5, 1e-8));
+        assert!(gradient_check(&softplus, &x, 1e-5, 1e-8));
+    }
+
+    fn stats(c: usize) -> (Var, Var) {
+        (
+            Variable::new(ArrayD::zeros(IxDyn(&[c]))),
+            Variable::new(ArrayD::ones(IxDyn(&[c]))),
+        )
+    }
+
+    #[test]
+    fn batch_norm_gradients() {
+        let x = randn(&[4, 3, 2, 2], 1);
+        let gamma = randn(&[3], 2);
+        let beta = randn(&[3], 3);
+        for train in [true, false] {
+            let _guard = (!train).then(core::test_mode);
+            let (mean, var) = stats(3);
+            let (g, b) = (gamma.clone(), beta.clone());
+            let f = |x: &Var| {
+                let (g, b) = (Variable::new(g.clone()), Variable::new(b.clone()));
+                weighted(batch_norm(x, &g, &b, &mean, &var, 0.9, 2e-5), 4)
+            };
+            assert!(gradient_check(&f, &x, 1e-5, 1e-7), "x, train = {}", train);
+            let f = |g: &Var| {
+                let b = Variable::new(beta.clone());
+                weighted(
+                    batch_norm(&Variable::new(x.clone()), g, &b, &mean, &var, 0.9, 2e-5),
+                    4,
+                )
+            };
+            assert!(
+                gradient_check(&f, &gamma, 1e-5, 1e-7),
+                "gamma, train = {}",
+                train
+            );
+            let f = |b: &Var| {
+                let g = Variable::new(gamma.clone());
+                weighted(
+                    batch_norm(&Variable::new(x.clone()), &g, b, &mean, &var, 0.9, 2e-5),
+                    4,
+                )
+            };
+            assert!(
+                gradient_check(&f, &beta, 1e-5, 1e-7),
+                "beta, train = {}",
+                train
+            );
+        }
+    }
+
+    #[test]
+    fn batch_norm_running_stats() {
+        let x = Variable::new(randn(&[8, 2, 3], 5) * 3.0 + 1.0);
+        let (beta, gamma) = stats(2);
+        let (mean, var) = stats(2);
+        batch_norm(&x, &gamma, &beta, &mean, &var, 0.9, 2e-5);
+        let rows = channel_rows(&x.borrow().data);
+        let m = rows.ncols() as f64;
+        let batch_mean = rows.mean_axis(Axis(1)).unwrap().into_dyn();
+        let batch_var = rows.var_axis(Axis(1), 0.0).into_dyn() * (m / (m - 1.0));
+        let expected_mean = &batch_mean * 0.1;
+        let expected_var = batch_var * 0.1 + 0.9;
+        assert!(utils::array_allclose(
+            &mean.data(),
+            &expected_mean,
+            1e-12,
+            1e-12
+        ));
+        assert!(utils::array_allclose(
+            &var.data(),
+            &expected_var,
+            1e-12,
+            1e-12
+        ));
+
+        let (trained_mean, trained_var) = (mean.data(), var.data());
+        let _guard = core::test_mode();
+        let y = batch_norm(&x, &gamma, &beta, &mean, &var, 0.9, 2e-5);
+        assert_eq!(mean.data(), trained_mean);
+        assert_eq!(var.data(), trained_var);
+        let inv_std = expected_var.mapv(|v| 1.0 / (v + 2e-5).sqrt());
+        let expected = from_channel_rows(
+            (&rows - &as_column(&expected_mean)) * as_column(&inv_std),
+            x.borrow().shape(),
+        );
+        assert!(utils::array_allclose(&y.data(), &expected, 1e-12, 1e-12));
+    }
+
+    #[test]
+    fn layer_norm_gradients() {
+        let x = randn(&[3, 4, 5], 6);
+        let gamma = randn(&[4, 5], 7);
+        let beta = randn(&[4, 5], 8);
+        let f = |x: &Var| {
+            let (g, b) = (Variable::new(gamma.clone()), Variable::new(beta.clone()));
+            weighted(layer_norm(x, &g, &b, 1e-5), 9)
+        };
+        assert!(gradient_check(&f, &x, 1e-5, 1e-7));
+        let f = |g: &Var| {
+            let b = Variable::new(beta.clone());
+            weighted(layer_norm(&Variable::new(x.clone()), g, &b, 1e-5), 9)
+        };
+        assert!(gradient_check(&f, &gamma, 1e-5, 1e-7));
+        let f = |b: &Var| {
+            let g = Variable::new(gamma.clone());
+            weighted(layer_norm(&Variable::new(x.clone()), &g, b, 1e-5), 9)
+        };
+        assert!(gradient_check(&f, &beta, 1e-5, 1e-7));
+    }
+
+    #[test]
+    fn group_norm_gradients() {
+        let x = randn(&[2, 6, 3, 2], 10);
+        let gamma = randn(&[6], 11);
+        let beta = randn(&[6], 12);
+        let f = |x: &Var| {
+            let (g, b) = (Variable::new(gamma.clone()), Variable::new(beta.clone()));
+            weighted(group_norm(x, &g, &b, 3, 1e-5), 13)
+        };
+        assert!(gradient_check(&f, &x, 1e-5, 1e-7));
+        let f = |g: &Var| {
+            let b = Variable::new(beta.clone());
+            weighted(group_norm(&Variable::new(x.clone()), g, &b, 3, 1e-5), 13)
+        };
+        assert!(gradient_check(&f, &gamma, 1e-5, 1e-7));
+        let f = |b: &Var| {
+            let g = Variable::new(gamma.clone());
+            weighted(group_norm(&Variable::new(x.clone()), &g, b, 3, 1e-5), 13)
+        };
+        assert!(gradient_check(&f, &beta, 1e-5, 1e-7));
+    }
+
+    #[test]
+    fn softmax_cross_entropy_large_logits() {
+        let x = Variable::new(arr2(&[[1000.0, -1000.0], [-1000.0, 1000.0]]));
+        let t = Variable::new(arr1(&[1.0, 1.0]));
+        let loss = softmax_cross_entropy(&x, &t);
+        assert_eq!(loss.data()[[]], 1000.0);
+        loss.backward();
+        let gx = x.grad().unwrap();
+        let expected = arr2(&[[0.5, -0.5], [0.0, 0.0]]).into_dyn();
+        assert!(utils::array_allclose(&gx, &expected, 0.0, 1e-12));
+    }
+
+    #[test]
+    #[should_panic(expected = "class labels must be integers in 0..3, got 3")]
+    fn softmax_cross_entropy_rejects_out_of_range_labels() {
+        let x = Variable::new(randn(&[2, 3], 14));
+        softmax_cross_entropy(&x, &Variable::new(arr1(&[0.0, 3.0])));
+    }
+}
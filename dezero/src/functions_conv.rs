@@ -0,0 +1,1139 @@
+use ndarray::{
+    s, Array2, Array4, Array5, Array6, ArrayD, ArrayView4, ArrayView6, Axis, Ix4, Ix6, IxDyn, Zip,
+};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::core::{call1, Function, Variable};
+use crate::functions::{reshape, slice_axis};
+
+/// DeZero's `pair`: a size given either once for both spatial axes or as
+/// `(height, width)`.
+pub trait IntoPair {
+    fn into_pair(self) -> (usize, usize);
+}
+
+impl IntoPair for usize {
+    fn into_pair(self) -> (usize, usize) {
+        (self, self)
+    }
+}
+
+impl IntoPair for (usize, usize) {
+    fn into_pair(self) -> (usize, usize) {
+        self
+    }
+}
+
+/// How a kernel walks over the input: step, zero padding on each side and
+/// spacing between kernel taps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvOpts {
+    pub stride: (usize, usize),
+    pub pad: (usize, usize),
+    pub dilation: (usize, usize),
+}
+
+impl Default for ConvOpts {
+    fn default() -> Self {
+        ConvOpts {
+            stride: (1, 1),
+            pad: (0, 0),
+            dilation: (1, 1),
+        }
+    }
+}
+
+impl ConvOpts {
+    pub fn new() -> Self {
+        ConvOpts::default()
+    }
+
+    pub fn stride(mut self, stride: impl IntoPair) -> Self {
+        self.stride = stride.into_pair();
+        self
+    }
+
+    pub fn pad(mut self, pad: impl IntoPair) -> Self {
+        self.pad = pad.into_pair();
+        self
+    }
+
+    pub fn dilation(mut self, dilation: impl IntoPair) -> Self {
+        self.dilation = dilation.into_pair();
+        self
+    }
+
+    /// Output height and width for an `h x w` input and `kernel`.
+    pub fn out_size(&self, (h, w): (usize, usize), kernel: (usize, usize)) -> (usize, usize) {
+        (
+            conv_outsize(h, kernel.0, self.stride.0, self.pad.0, self.dilation.0),
+            conv_outsize(w, kernel.1, self.stride.1, self.pad.1, self.dilation.1),
+        )
+    }
+}
+
+pub fn conv_outsize(
+    size: usize,
+    kernel: usize,
+    stride: usize,
+    pad: usize,
+    dilation: usize,
+) -> usize {
+    let span = dilation * (kernel - 1) + 1;
+    assert!(
+        size + 2 * pad >= span,
+        "kernel spans {} but the padded input is only {}",
+        span,
+        size + 2 * pad
+    );
+    (size + 2 * pad - span) / stride + 1
+}
+
+fn as4(x: &ArrayD<f64>) -> ArrayView4<'_, f64> {
+    x.view()
+        .into_dimensionality::<Ix4>()
+        .expect("expected an [N, C, H, W] array")
+}
+
+/// Gathers every kernel window of `x` (`[N, C, H, W]`) into
+/// `[N, C, KH, KW, OH, OW]`.
+pub fn im2col_array(x: &ArrayD<f64>, kernel: (usize, usize), opts: &ConvOpts) -> Array6<f64> {
+    let x = as4(x);
+    let (n, c, h, w) = x.dim();
+    let (kh, kw) = kernel;
+    let (sh, sw) = opts.stride;
+    let (ph, pw) = opts.pad;
+    let (dh, dw) = opts.dilation;
+    let (oh, ow) = opts.out_size((h, w), kernel);
+
+    let mut img = Array4::zeros((n, c, h + 2 * ph, w + 2 * pw));
+    img.slice_mut(s![.., .., ph..ph + h, pw..pw + w]).assign(&x);
+    let mut col = Array6::zeros((n, c, kh, kw, oh, ow));
+    for i in 0..kh {
+        let y0 = i * dh;
+        for j in 0..kw {
+            let x0 = j * dw;
+            let window = img.slice(s![
+                ..,
+                ..,
+                y0..y0 + sh * (oh - 1) + 1;sh,
+                x0..x0 + sw * (ow - 1) + 1;sw
+            ]);
+            col.slice_mut(s![.., .., i, j, .., ..]).assign(&window);
+        }
+    }
+    col
+}
+
+/// Inverse layout of `im2col_array`: sums every window back into an
+/// `[N, C, H, W]` image of `shape`, where windows overlap.
+pub fn col2im_array(col: &Array6<f64>, shape: &[usize], opts: &ConvOpts) -> ArrayD<f64> {
+    let (n, c, kh, kw, oh, ow) = col.dim();
+    let (h, w) = (shape[2], shape[3]);
+    let (sh, sw) = opts.stride;
+    let (ph, pw) = opts.pad;
+    let (dh, dw) = opts.dilation;
+
+    let mut img = Array4::zeros((n, c, h + 2 * ph, w + 2 * pw));
+    for i in 0..kh {
+        let y0 = i * dh;
+        for j in 0..kw {
+            let x0 = j * dw;
+            let mut window = img.slice_mut(s![
+                ..,
+                ..,
+                y0..y0 + sh * (oh - 1) + 1;sh,
+                x0..x0 + sw * (ow - 1) + 1;sw
+            ]);
+            window += &col.slice(s![.., .., i, j, .., ..]);
+        }
+    }
+    img.slice(s![.., .., ph..ph + h, pw..pw + w])
+        .to_owned()
+        .into_dyn()
+}
+
+/// `[N, C, KH, KW, OH, OW]` as one row per output position:
+/// `[N * OH * OW, C * KH * KW]`.
+fn col_to_matrix(col: ArrayView6<f64>) -> Array2<f64> {
+    let (n, c, kh, kw, oh, ow) = col.dim();
+    let col = col.permuted_axes([0, 4, 5, 1, 2, 3]);
+    let col = col.as_standard_layout().into_owned();
+    col.into_shape((n * oh * ow, c * kh * kw)).unwrap()
+}
+
+fn matrix_to_col(m: Array2<f64>, dim: (usize, usize, usize, usize, usize, usize)) -> Array6<f64> {
+    let (n, c, kh, kw, oh, ow) = dim;
+    m.into_shape((n, oh, ow, c, kh, kw))
+        .unwrap()
+        .permuted_axes([0, 3, 4, 5, 1, 2])
+}
+
+pub struct Im2col {
+    kernel: (usize, usize),
+    opts: ConvOpts,
+    to_matrix: bool,
+}
+
+impl Function for Im2col {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = im2col_array(xs[0], self.kernel, &self.opts);
+        if self.to_matrix {
+            vec![col_to_matrix(col.view()).into_dyn()]
+        } else {
+            vec![col.into_dyn()]
+        }
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = if self.to_matrix {
+            let (n, c, h, w) = as4(xs[0]).dim();
+            let (oh, ow) = self.opts.out_size((h, w), self.kernel);
+            let (kh, kw) = self.kernel;
+            let m = gys[0].view().into_dimensionality().unwrap().to_owned();
+            matrix_to_col(m, (n, c, kh, kw, oh, ow))
+        } else {
+            gys[0]
+                .view()
+                .into_dimensionality::<Ix6>()
+                .unwrap()
+                .to_owned()
+        };
+        vec![col2im_array(&col, xs[0].shape(), &self.opts)]
+    }
+}
+
+/// Differentiable `im2col`. With `to_matrix` the result is
+/// `[N * OH * OW, C * KH * KW]`, otherwise `[N, C, KH, KW, OH, OW]`.
+pub fn im2col(
+    x: &Rc<RefCell<Variable>>,
+    kernel: impl IntoPair,
+    opts: ConvOpts,
+    to_matrix: bool,
+) -> Rc<RefCell<Variable>> {
+    let f = Im2col {
+        kernel: kernel.into_pair(),
+        opts,
+        to_matrix,
+    };
+    call1(f, &[x])
+}
+
+pub struct Col2im {
+    shape: Vec<usize>,
+    kernel: (usize, usize),
+    opts: ConvOpts,
+    to_matrix: bool,
+}
+
+impl Function for Col2im {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = if self.to_matrix {
+            let (n, c) = (self.shape[0], self.shape[1]);
+            let (oh, ow) = self
+                .opts
+                .out_size((self.shape[2], self.shape[3]), self.kernel);
+            let (kh, kw) = self.kernel;
+            let m = xs[0].view().into_dimensionality().unwrap().to_owned();
+            matrix_to_col(m, (n, c, kh, kw, oh, ow))
+        } else {
+            xs[0]
+                .view()
+                .into_dimensionality::<Ix6>()
+                .unwrap()
+                .to_owned()
+        };
+        vec![col2im_array(&col, &self.shape, &self.opts)]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = im2col_array(&gys[0], self.kernel, &self.opts);
+        if self.to_matrix {
+            vec![col_to_matrix(col.view()).into_dyn()]
+        } else {
+            vec![col.into_dyn()]
+        }
+    }
+}
+
+/// Differentiable inverse of `im2col` back to an image of `shape`
+/// (`[N, C, H, W]`); overlapping windows are summed.
+pub fn col2im(
+    col: &Rc<RefCell<Variable>>,
+    shape: &[usize],
+    kernel: impl IntoPair,
+    opts: ConvOpts,
+    to_matrix: bool,
+) -> Rc<RefCell<Variable>> {
+    let f = Col2im {
+        shape: shape.to_vec(),
+        kernel: kernel.into_pair(),
+        opts,
+        to_matrix,
+    };
+    call1(f, &[col])
+}
+
+/// 2-D convolution (cross-correlation) of `[N, C, H, W]` input with
+/// `[OC, C / groups, KH, KW]` weights via im2col and one matrix product per
+/// group. The im2col matrices are kept for the backward pass.
+pub struct Conv2d {
+    opts: ConvOpts,
+    groups: usize,
+    cols: Vec<Array2<f64>>,
+}
+
+impl Conv2d {
+    fn group_weight(w: &ArrayD<f64>, g: usize, groups: usize) -> Array2<f64> {
+        let oc_g = w.shape()[0] / groups;
+        let w = w.slice_axis(Axis(0), (g * oc_g..(g + 1) * oc_g).into());
+        let k = w.len() / oc_g;
+        w.as_standard_layout()
+            .into_owned()
+            .into_shape((oc_g, k))
+            .unwrap()
+    }
+}
+
+impl Function for Conv2d {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, w) = (xs[0], xs[1]);
+        let (n, c, _, _) = as4(x).dim();
+        let (oc, c_g, kh, kw) = as4(w).dim();
+        let groups = self.groups;
+        assert!(
+            c == c_g * groups && oc % groups == 0,
+            "conv2d got {} input channels and weights {:?} with {} groups",
+            c,
+            w.shape(),
+            groups
+        );
+        let col = im2col_array(x, (kh, kw), &self.opts);
+        let (oh, ow) = (col.dim().4, col.dim().5);
+        let oc_g = oc / groups;
+
+        let mut y = Array2::zeros((n * oh * ow, oc));
+        self.cols.clear();
+        for g in 0..groups {
+            let col_g = col_to_matrix(col.slice(s![.., g * c_g..(g + 1) * c_g, .., .., .., ..]));
+            let w_g = Conv2d::group_weight(w, g, groups);
+            y.slice_mut(s![.., g * oc_g..(g + 1) * oc_g])
+                .assign(&col_g.dot(&w_g.t()));
+            self.cols.push(col_g);
+        }
+        if let Some(b) = xs.get(2) {
+            y += &b.view().into_shape(oc).unwrap();
+        }
+        let y = y
+            .into_shape((n, oh, ow, oc))
+            .unwrap()
+            .permuted_axes([0, 3, 1, 2]);
+        vec![y.as_standard_layout().into_owned().into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, w) = (xs[0], xs[1]);
+        let (n, c, _, _) = as4(x).dim();
+        let (oc, c_g, kh, kw) = as4(w).dim();
+        let (_, _, oh, ow) = as4(&gys[0]).dim();
+        let groups = self.groups;
+        let oc_g = oc / groups;
+
+        let gy = as4(&gys[0]).permuted_axes([0, 2, 3, 1]);
+        let gy = gy
+            .as_standard_layout()
+            .into_owned()
+            .into_shape((n * oh * ow, oc))
+            .unwrap();
+        let mut gw = Array2::zeros((oc, c_g * kh * kw));
+        let mut gcol = Array6::zeros((n, c, kh, kw, oh, ow));
+        for g in 0..groups {
+            let gy_g = gy.slice(s![.., g * oc_g..(g + 1) * oc_g]);
+            let w_g = Conv2d::group_weight(w, g, groups);
+            gw.slice_mut(s![g * oc_g..(g + 1) * oc_g, ..])
+                .assign(&gy_g.t().dot(&self.cols[g]));
+            let gcol_g = matrix_to_col(gy_g.dot(&w_g), (n, c_g, kh, kw, oh, ow));
+            gcol.slice_mut(s![.., g * c_g..(g + 1) * c_g, .., .., .., ..])
+                .assign(&gcol_g);
+        }
+        let mut gxs = vec![
+            col2im_array(&gcol, x.shape(), &self.opts),
+            gw.into_shape(IxDyn(w.shape())).unwrap(),
+        ];
+        if xs.len() == 3 {
+            gxs.push(gy.sum_axis(Axis(0)).into_dyn());
+        }
+        gxs
+    }
+}
+
+/// `[N, C, H, W]` input, `[OC, C / groups, KH, KW]` weights and an optional
+/// `[OC]` bias give `[N, OC, OH, OW]`.
+pub fn conv2d(
+    x: &Rc<RefCell<Variable>>,
+    w: &Rc<RefCell<Variable>>,
+    b: Option<&Rc<RefCell<Variable>>>,
+    opts: ConvOpts,
+    groups: usize,
+) -> Rc<RefCell<Variable>> {
+    let f = Conv2d {
+        opts,
+        groups,
+        cols: Vec::new(),
+    };
+    match b {
+        Some(b) => call1(f, &[x, w, b]),
+        None => call1(f, &[x, w]),
+    }
+}
+
+/// Windows of `x` as `[N, C, KH * KW, OH, OW]`, with padded taps set to
+/// `fill` so they never win a max.
+fn pool_windows(
+    x: &ArrayD<f64>,
+    kernel: (usize, usize),
+    opts: &ConvOpts,
+    fill: f64,
+) -> Array5<f64> {
+    let mut col = im2col_array(x, kernel, opts);
+    if opts.pad != (0, 0) {
+        let valid = im2col_array(&ArrayD::ones(x.raw_dim()), kernel, opts);
+        Zip::from(&mut col).and(&valid).for_each(|c, &v| {
+            if v == 0.0 {
+                *c = fill
+            }
+        });
+    }
+    let (n, c, kh, kw, oh, ow) = col.dim();
+    col.into_shape((n, c, kh * kw, oh, ow)).unwrap()
+}
+
+/// Max pooling. The flat in-window position of each maximum (the first one
+/// on ties) is kept so that backward routes the gradient there alone.
+pub struct MaxPool2d {
+    kernel: (usize, usize),
+    opts: ConvOpts,
+    argmax: Array4<usize>,
+}
+
+impl Function for MaxPool2d {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = pool_windows(xs[0], self.kernel, &self.opts, f64::NEG_INFINITY);
+        let (n, c, _, oh, ow) = col.dim();
+        let mut y = Array4::zeros((n, c, oh, ow));
+        self.argmax = Array4::zeros((n, c, oh, ow));
+        Zip::from(&mut y)
+            .and(&mut self.argmax)
+            .and(col.lanes(Axis(2)))
+            .for_each(|y, arg, lane| {
+                let (mut best, mut best_i) = (f64::NEG_INFINITY, 0);
+                for (i, &v) in lane.iter().enumerate() {
+                    if v > best {
+                        best = v;
+                        best_i = i;
+                    }
+                }
+                *y = best;
+                *arg = best_i;
+            });
+        vec![y.into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (n, c, oh, ow) = self.argmax.dim();
+        let (kh, kw) = self.kernel;
+        let gy = as4(&gys[0]);
+        let mut gcol = Array5::zeros((n, c, kh * kw, oh, ow));
+        for ((ni, ci, y, x), &arg) in self.argmax.indexed_iter() {
+            gcol[[ni, ci, arg, y, x]] = gy[[ni, ci, y, x]];
+        }
+        let gcol = gcol.into_shape((n, c, kh, kw, oh, ow)).unwrap();
+        vec![col2im_array(&gcol, xs[0].shape(), &self.opts)]
+    }
+}
+
+/// Max over each `kernel` window of `[N, C, H, W]` input. Padding never
+/// contributes to the max. Note `opts.stride` defaults to 1, not `kernel`.
+pub fn max_pool2d(
+    x: &Rc<RefCell<Variable>>,
+    kernel: impl IntoPair,
+    opts: ConvOpts,
+) -> Rc<RefCell<Variable>> {
+    let f = MaxPool2d {
+        kernel: kernel.into_pair(),
+        opts,
+        argmax: Array4::zeros((0, 0, 0, 0)),
+    };
+    call1(f, &[x])
+}
+
+/// Average pooling; padded taps count as zeros in the `KH * KW` average.
+pub struct AvgPool2d {
+    kernel: (usize, usize),
+    opts: ConvOpts,
+}
+
+impl Function for AvgPool2d {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let col = pool_windows(xs[0], self.kernel, &self.opts, 0.0);
+        vec![col.mean_axis(Axis(2)).unwrap().into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (kh, kw) = self.kernel;
+        let gy = as4(&gys[0]);
+        let (n, c, oh, ow) = gy.dim();
+        let scale = 1.0 / (kh * kw) as f64;
+        let gcol = gy
+            .insert_axis(Axis(2))
+            .insert_axis(Axis(2))
+            .broadcast((n, c, kh, kw, oh, ow))
+            .unwrap()
+            .mapv(|g| g * scale);
+        vec![col2im_array(&gcol, xs[0].shape(), &self.opts)]
+    }
+}
+
+/// Mean over each `kernel` window of `[N, C, H, W]` input.
+pub fn avg_pool2d(
+    x: &Rc<RefCell<Variable>>,
+    kernel: impl IntoPair,
+    opts: ConvOpts,
+) -> Rc<RefCell<Variable>> {
+    let f = AvgPool2d {
+        kernel: kernel.into_pair(),
+        opts,
+    };
+    call1(f, &[x])
+}
+
+/// Input rows `[floor(i * size / out), ceil((i + 1) * size / out))` of bin `i`.
+fn adaptive_bins(size: usize, out: usize) -> Bins {
+    (0..out)
+        .map(|i| (i * size / out, ((i + 1) * size).div_ceil(out)))
+        .collect()
+}
+
+/// Averages over bins chosen so the output is exactly `output_size`; bins
+/// may overlap by one when the sizes do not divide.
+pub struct AdaptiveAvgPool2d {
+    output_size: (usize, usize),
+}
+
+/// Input rows of each output bin, one list per spatial axis.
+type Bins = Vec<(usize, usize)>;
+
+impl AdaptiveAvgPool2d {
+    fn bins(&self, shape: &[usize]) -> (Bins, Bins) {
+        (
+            adaptive_bins(shape[2], self.output_size.0),
+            adaptive_bins(shape[3], self.output_size.1),
+        )
+    }
+}
+
+impl Function for AdaptiveAvgPool2d {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let x = as4(xs[0]);
+        let (n, c, _, _) = x.dim();
+        let (rows, cols) = self.bins(xs[0].shape());
+        let mut y = Array4::zeros((n, c, rows.len(), cols.len()));
+        for (i, &(y0, y1)) in rows.iter().enumerate() {
+            for (j, &(x0, x1)) in cols.iter().enumerate() {
+                let window = x.slice(s![.., .., y0..y1, x0..x1]);
+                let area = ((y1 - y0) * (x1 - x0)) as f64;
+                let sum = window.sum_axis(Axis(3)).sum_axis(Axis(2));
+                y.slice_mut(s![.., .., i, j]).assign(&(sum / area));
+            }
+        }
+        vec![y.into_dyn()]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let gy = as4(&gys[0]);
+        let (rows, cols) = self.bins(xs[0].shape());
+        let mut gx = ArrayD::zeros(xs[0].raw_dim());
+        let mut gx4 = gx.view_mut().into_dimensionality::<Ix4>().unwrap();
+        for (i, &(y0, y1)) in rows.iter().enumerate() {
+            for (j, &(x0, x1)) in cols.iter().enumerate() {
+                let area = ((y1 - y0) * (x1 - x0)) as f64;
+                let g = gy.slice(s![.., .., i..i + 1, j..j + 1]).mapv(|g| g / area);
+                let mut window = gx4.slice_mut(s![.., .., y0..y1, x0..x1]);
+                window += &g;
+            }
+        }
+        vec![gx]
+    }
+}
+
+/// Average pooling to a fixed `[N, C, OH, OW]` whatever the input size.
+pub fn adaptive_avg_pool2d(
+    x: &Rc<RefCell<Variable>>,
+    output_size: impl IntoPair,
+) -> Rc<RefCell<Variable>> {
+    let f = AdaptiveAvgPool2d {
+        output_size: output_size.into_pair(),
+    };
+    call1(f, &[x])
+}
+
+/// Mean over the spatial axes: `[N, C, H, W]` to `[N, C]`.
+pub fn global_avg_pool2d(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    let shape = x.borrow().shape().to_vec();
+    let y = adaptive_avg_pool2d(x, 1);
+    reshape(&y, &shape[..2])
+}
+
+/// Max over the spatial axes: `[N, C, H, W]` to `[N, C]`.
+pub fn global_max_pool2d(x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    let shape = x.borrow().shape().to_vec();
+    let y = max_pool2d(x, (shape[2], shape[3]), ConvOpts::default());
+    reshape(&y, &shape[..2])
+}
+
+/// Transposed convolution: the adjoint of `Conv2d` with the same `opts`,
+/// computed as one matrix product per group followed by `col2im`.
+/// Weights are `[C, OC / groups, KH, KW]`.
+pub struct ConvTranspose2d {
+    opts: ConvOpts,
+    groups: usize,
+    output_padding: (usize, usize),
+}
+
+impl ConvTranspose2d {
+    /// `[N, C, H, W]` as `[N * H * W, C]`.
+    fn pixel_rows(x: ArrayView4<f64>) -> Array2<f64> {
+        let (n, c, h, w) = x.dim();
+        let x = x.permuted_axes([0, 2, 3, 1]);
+        x.as_standard_layout()
+            .into_owned()
+            .into_shape((n * h * w, c))
+            .unwrap()
+    }
+
+    fn from_pixel_rows(
+        rows: Array2<f64>,
+        (n, c, h, w): (usize, usize, usize, usize),
+    ) -> Array4<f64> {
+        rows.into_shape((n, h, w, c))
+            .unwrap()
+            .permuted_axes([0, 3, 1, 2])
+    }
+}
+
+/// `(size - 1) * stride - 2 * pad + dilation * (kernel - 1) + 1 + output_padding`.
+pub fn conv_transpose_outsize(
+    size: usize,
+    kernel: usize,
+    stride: usize,
+    pad: usize,
+    dilation: usize,
+    output_padding: usize,
+) -> usize {
+    ((size - 1) * stride + dilation * (kernel - 1) + 1 + output_padding)
+        .checked_sub(2 * pad)
+        .expect("padding larger than the transposed convolution output")
+}
+
+impl Function for ConvTranspose2d {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, w) = (as4(xs[0]), as4(xs[1]));
+        let (n, c, h, wd) = x.dim();
+        let (wc, oc_g, kh, kw) = w.dim();
+        let groups = self.groups;
+        assert!(
+            c == wc && c % groups == 0,
+            "conv_transpose2d got {} input channels and weights {:?} with {} groups",
+            c,
+            w.shape(),
+            groups
+        );
+        let c_g = c / groups;
+        let o = &self.opts;
+        let oh = conv_transpose_outsize(
+            h,
+            kh,
+            o.stride.0,
+            o.pad.0,
+            o.dilation.0,
+            self.output_padding.0,
+        );
+        let ow = conv_transpose_outsize(
+            wd,
+            kw,
+            o.stride.1,
+            o.pad.1,
+            o.dilation.1,
+            self.output_padding.1,
+        );
+
+        let rows = ConvTranspose2d::pixel_rows(x);
+        let mut col = Array6::zeros((n, oc_g * groups, kh, kw, h, wd));
+        for g in 0..groups {
+            let x_g = rows.slice(s![.., g * c_g..(g + 1) * c_g]);
+            let w_g = Conv2d::group_weight(xs[1], g, groups);
+            let col_g = matrix_to_col(x_g.dot(&w_g), (n, oc_g, kh, kw, h, wd));
+            col.slice_mut(s![.., g * oc_g..(g + 1) * oc_g, .., .., .., ..])
+                .assign(&col_g);
+        }
+        let mut y = col2im_array(&col, &[n, oc_g * groups, oh, ow], o);
+        if let Some(b) = xs.get(2) {
+            y += &per_channel(b);
+        }
+        vec![y]
+    }
+
+    fn backward(&mut self, xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (x, w) = (as4(xs[0]), as4(xs[1]));
+        let (n, c, h, wd) = x.dim();
+        let (_, oc_g, kh, kw) = w.dim();
+        let groups = self.groups;
+        let c_g = c / groups;
+
+        // With `output_padding` at or above the stride (allowed when the
+        // dilation is larger), `gy` has room for extra windows past the last
+        // input position. Nothing in `x` fed them, so they are dropped.
+        let gcol = im2col_array(&gys[0], (kh, kw), &self.opts);
+        let gcol = gcol.slice(s![.., .., .., .., ..h, ..wd]);
+        let rows = ConvTranspose2d::pixel_rows(x);
+        let mut gx = Array2::zeros((n * h * wd, c));
+        let mut gw = Array2::zeros((c, oc_g * kh * kw));
+        for g in 0..groups {
+            let gcol_g =
+                col_to_matrix(gcol.slice(s![.., g * oc_g..(g + 1) * oc_g, .., .., .., ..]));
+            let w_g = Conv2d::group_weight(xs[1], g, groups);
+            gx.slice_mut(s![.., g * c_g..(g + 1) * c_g])
+                .assign(&gcol_g.dot(&w_g.t()));
+            let x_g = rows.slice(s![.., g * c_g..(g + 1) * c_g]);
+            gw.slice_mut(s![g * c_g..(g + 1) * c_g, ..])
+                .assign(&x_g.t().dot(&gcol_g));
+        }
+        let gx = ConvTranspose2d::from_pixel_rows(gx, (n, c, h, wd));
+        let mut gxs = vec![
+            gx.as_standard_layout().into_owned().into_dyn(),
+            gw.into_shape(IxDyn(w.shape())).unwrap(),
+        ];
+        if xs.len() == 3 {
+            let gb = as4(&gys[0])
+                .sum_axis(Axis(3))
+                .sum_axis(Axis(2))
+                .sum_axis(Axis(0));
+            gxs.push(gb.into_dyn());
+        }
+        gxs
+    }
+}
+
+/// `[N, C, H, W]` input and `[C, OC / groups, KH, KW]` weights give
+/// `[N, OC, OH, OW]`, sized by `conv_transpose_outsize`. `output_padding`
+/// (smaller than the stride or the dilation) picks among the input sizes that a strided
+/// `conv2d` maps to the same output size.
+pub fn conv_transpose2d(
+    x: &Rc<RefCell<Variable>>,
+    w: &Rc<RefCell<Variable>>,
+    b: Option<&Rc<RefCell<Variable>>>,
+    opts: ConvOpts,
+    groups: usize,
+    output_padding: impl IntoPair,
+) -> Rc<RefCell<Variable>> {
+    let output_padding = output_padding.into_pair();
+    assert!(
+        output_padding.0 < opts.stride.0.max(opts.dilation.0)
+            && output_padding.1 < opts.stride.1.max(opts.dilation.1),
+        "output_padding must be smaller than stride or dilation"
+    );
+    let f = ConvTranspose2d {
+        opts,
+        groups,
+        output_padding,
+    };
+    match b {
+        Some(b) => call1(f, &[x, w, b]),
+        None => call1(f, &[x, w]),
+    }
+}
+
+fn per_channel(b: &ArrayD<f64>) -> ArrayD<f64> {
+    b.to_shape(IxDyn(&[1, b.len(), 1, 1])).unwrap().into_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsampleMode {
+    Nearest,
+    /// Linear along each axis with half-pixel centers (`align_corners`
+    /// off), clamped at the borders.
+    Bilinear,
+}
+
+/// `[out, size]` matrix mapping one axis of the input to the upsampled axis.
+fn interp_matrix(size: usize, scale: usize, mode: UpsampleMode) -> Array2<f64> {
+    let mut a = Array2::zeros((size * scale, size));
+    for o in 0..size * scale {
+        match mode {
+            UpsampleMode::Nearest => a[[o, o / scale]] = 1.0,
+            UpsampleMode::Bilinear => {
+                let src = ((o as f64 + 0.5) / scale as f64 - 0.5).max(0.0);
+                let i0 = (src.floor() as usize).min(size - 1);
+                let i1 = (i0 + 1).min(size - 1);
+                let l = src - i0 as f64;
+                a[[o, i0]] += 1.0 - l;
+                a[[o, i1]] += l;
+            }
+        }
+    }
+    a
+}
+
+/// Resamples every `[H, W]` plane as `A_h · x · A_wᵀ`, so backward is the
+/// same product with the transposed matrices.
+pub struct Upsample {
+    ah: Array2<f64>,
+    aw: Array2<f64>,
+}
+
+impl Upsample {
+    fn apply(x: &ArrayD<f64>, ah: &Array2<f64>, aw: &Array2<f64>) -> ArrayD<f64> {
+        let x = as4(x);
+        let (n, c, _, _) = x.dim();
+        let mut y = Array4::zeros((n, c, ah.nrows(), aw.nrows()));
+        for (mut y, x) in y.outer_iter_mut().zip(x.outer_iter()) {
+            for (mut y, x) in y.outer_iter_mut().zip(x.outer_iter()) {
+                y.assign(&ah.dot(&x).dot(&aw.t()));
+            }
+        }
+        y.into_dyn()
+    }
+}
+
+impl Function for Upsample {
+    fn forward(&mut self, xs: &[&ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        vec![Upsample::apply(xs[0], &self.ah, &self.aw)]
+    }
+
+    fn backward(&mut self, _xs: &[&ArrayD<f64>], gys: &[ArrayD<f64>]) -> Vec<ArrayD<f64>> {
+        let (ah, aw) = (self.ah.t().to_owned(), self.aw.t().to_owned());
+        vec![Upsample::apply(&gys[0], &ah, &aw)]
+    }
+}
+
+/// Enlarges `[N, C, H, W]` input by an integer `scale` per spatial axis.
+pub fn upsample(
+    x: &Rc<RefCell<Variable>>,
+    scale: impl IntoPair,
+    mode: UpsampleMode,
+) -> Rc<RefCell<Variable>> {
+    let (sh, sw) = scale.into_pair();
+    let (h, w) = {
+        let x = x.borrow();
+        (x.shape()[2], x.shape()[3])
+    };
+    let f = Upsample {
+        ah: interp_matrix(h, sh, mode),
+        aw: interp_matrix(w, sw, mode),
+    };
+    call1(f, &[x])
+}
+
+/// Stride, padding and dilation of a 1-D convolution. With `causal` set,
+/// the input is padded on the left only, by `dilation * (K - 1)` (`pad` is
+/// ignored), so output step `t` never sees input after `t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conv1dOpts {
+    pub stride: usize,
+    pub pad: usize,
+    pub dilation: usize,
+    pub causal: bool,
+}
+
+impl Default for Conv1dOpts {
+    fn default() -> Self {
+        Conv1dOpts {
+            stride: 1,
+            pad: 0,
+            dilation: 1,
+            causal: false,
+        }
+    }
+}
+
+impl Conv1dOpts {
+    pub fn new() -> Self {
+        Conv1dOpts::default()
+    }
+
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    pub fn pad(mut self, pad: usize) -> Self {
+        self.pad = pad;
+        self
+    }
+
+    pub fn dilation(mut self, dilation: usize) -> Self {
+        self.dilation = dilation;
+        self
+    }
+
+    pub fn causal(mut self, causal: bool) -> Self {
+        self.causal = causal;
+        self
+    }
+}
+
+/// `[N, C, L]` input and `[OC, C / groups, K]` weights give `[N, OC, OL]`.
+///
+/// Runs as `conv2d` on a height-1 image. A causal convolution pads both
+/// sides by `dilation * (K - 1)` and keeps the leading outputs, whose windows
+/// are exactly those of left-only padding.
+pub fn conv1d(
+    x: &Rc<RefCell<Variable>>,
+    w: &Rc<RefCell<Variable>>,
+    b: Option<&Rc<RefCell<Variable>>>,
+    opts: Conv1dOpts,
+    groups: usize,
+) -> Rc<RefCell<Variable>> {
+    let (n, c, l) = match x.borrow().shape() {
+        &[n, c, l] => (n, c, l),
+        s => panic!("conv1d expects [N, C, L] input, got {:?}", s),
+    };
+    let (oc, c_g, k) = match w.borrow().shape() {
+        &[oc, c_g, k] => (oc, c_g, k),
+        s => panic!("conv1d expects [OC, C / groups, K] weights, got {:?}", s),
+    };
+    let pad = if opts.causal {
+        opts.dilation * (k - 1)
+    } else {
+        opts.pad
+    };
+    let opts2d = ConvOpts::new()
+        .stride((1, opts.stride))
+        .pad((0, pad))
+        .dilation((1, opts.dilation));
+    let x = reshape(x, &[n, c, 1, l]);
+    let w = reshape(w, &[oc, c_g, 1, k]);
+    let y = conv2d(&x, &w, b, opts2d, groups);
+    let ol = y.borrow().shape()[3];
+    let y = reshape(&y, &[n, oc, ol]);
+    if opts.causal {
+        slice_axis(&y, 2, 0..(l - 1) / opts.stride + 1)
+    } else {
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::VariableExt;
+    use crate::layers::{self, Layer};
+    use crate::utils::testing::{randn, weighted, Var};
+    use crate::utils::{array_allclose, gradient_check};
+
+    /// Direct six-loop convolution to check `conv2d` against.
+    fn naive_conv2d(
+        x: &ArrayD<f64>,
+        w: &ArrayD<f64>,
+        b: Option<&ArrayD<f64>>,
+        opts: &ConvOpts,
+        groups: usize,
+    ) -> ArrayD<f64> {
+        let (x, w) = (as4(x), as4(w));
+        let (n, c, h, wd) = x.dim();
+        let (oc, c_g, kh, kw) = w.dim();
+        let oc_g = oc / groups;
+        assert_eq!(c_g * groups, c);
+        let (oh, ow) = opts.out_size((h, wd), (kh, kw));
+        let mut y = Array4::zeros((n, oc, oh, ow));
+        for ((ni, o, i, j), y) in y.indexed_iter_mut() {
+            let g = o / oc_g;
+            let mut acc = b.map_or(0.0, |b| b[o]);
+            for ci in 0..c_g {
+                for ki in 0..kh {
+                    for kj in 0..kw {
+                        let yy = (i * opts.stride.0 + ki * opts.dilation.0) as isize
+                            - opts.pad.0 as isize;
+                        let xx = (j * opts.stride.1 + kj * opts.dilation.1) as isize
+                            - opts.pad.1 as isize;
+                        if yy < 0 || xx < 0 || yy >= h as isize || xx >= wd as isize {
+                            continue;
+                        }
+                        acc += x[[ni, g * c_g + ci, yy as usize, xx as usize]] * w[[o, ci, ki, kj]];
+                    }
+                }
+            }
+            *y = acc;
+        }
+        y.into_dyn()
+    }
+
+    fn conv_cases() -> Vec<(ConvOpts, usize)> {
+        vec![
+            (ConvOpts::new(), 1),
+            (ConvOpts::new().stride(2).pad(1), 1),
+            (ConvOpts::new().pad((1, 2)).dilation(2), 1),
+            (ConvOpts::new().stride((2, 1)).pad(1).dilation((1, 2)), 2),
+            (ConvOpts::new().stride(2).pad(1).dilation(2), 4),
+        ]
+    }
+
+    #[test]
+    fn conv2d_matches_naive_reference() {
+        for (k, (opts, groups)) in conv_cases().into_iter().enumerate() {
+            let seed = k as u64 * 3;
+            let x = randn(&[2, 4, 7, 6], seed);
+            let w = randn(&[8, 4 / groups, 3, 2], seed + 1);
+            let b = randn(&[8], seed + 2);
+            let y = conv2d(
+                &Variable::new(x.clone()),
+                &Variable::new(w.clone()),
+                Some(&Variable::new(b.clone())),
+                opts,
+                groups,
+            );
+            let expected = naive_conv2d(&x, &w, Some(&b), &opts, groups);
+            assert!(
+                array_allclose(&y.data(), &expected, 1e-12, 1e-12),
+                "{:?} with {} groups",
+                opts,
+                groups
+            );
+        }
+    }
+
+    #[test]
+    fn conv2d_layer_matches_naive_reference() {
+        let opts = ConvOpts::new().stride(2).pad(1).dilation(2);
+        let mut layer = layers::Conv2d::new(6, 3)
+            .stride(2)
+            .pad(1)
+            .dilation(2)
+            .groups(3)
+            .with_in_channels(3);
+        let x = randn(&[2, 3, 9, 8], 20);
+        let y = layer.forward(&Variable::new(x.clone()));
+        let b = layer.b.as_ref().unwrap().data();
+        let expected = naive_conv2d(&x, &layer.w.data(), Some(&b), &opts, 3);
+        assert!(array_allclose(&y.data(), &expected, 1e-12, 1e-12));
+    }
+
+    #[test]
+    fn im2col_col2im_gradients() {
+        let opts = ConvOpts::new().stride(2).pad(1).dilation((1, 2));
+        let x = randn(&[2, 3, 6, 7], 30);
+        for to_matrix in [false, true] {
+            let f = |x: &Var| weighted(im2col(x, 3, opts, to_matrix), 31);
+            assert!(gradient_check(&f, &x, 1e-5, 1e-8), "im2col {}", to_matrix);
+
+            let col_shape = im2col(&Variable::new(x.clone()), 3, opts, to_matrix).shape();
+            let col = randn(&col_shape, 32);
+            let f = |c: &Var| weighted(col2im(c, x.shape(), 3, opts, to_matrix), 33);
+            assert!(gradient_check(&f, &col, 1e-4, 1e-6), "col2im {}", to_matrix);
+        }
+    }
+
+    #[test]
+    fn conv2d_gradients() {
+        for (k, (opts, groups)) in conv_cases().into_iter().enumerate() {
+            let seed = 40 + k as u64 * 4;
+            let x = randn(&[2, 4, 6, 5], seed);
+            let w = randn(&[4, 4 / groups, 3, 2], seed + 1);
+            let b = randn(&[4], seed + 2);
+            let conv =
+                |x: &Var, w: &Var, b: &Var| weighted(conv2d(x, w, Some(b), opts, groups), seed + 3);
+            let (wv, bv) = (Variable::new(w.clone()), Variable::new(b.clone()));
+            assert!(gradient_check(&|x: &Var| conv(x, &wv, &bv), &x, 1e-5, 1e-7));
+            let xv = Variable::new(x.clone());
+            assert!(gradient_check(&|w: &Var| conv(&xv, w, &bv), &w, 1e-5, 1e-7));
+            assert!(gradient_check(&|b: &Var| conv(&xv, &wv, b), &b, 1e-5, 1e-7));
+        }
+    }
+
+    #[test]
+    fn conv_transpose2d_output_padding_beyond_stride() {
+        // Stride 1 with dilation 2 allows output_padding 1, which leaves
+        // `gy` one row and column wider than the windows `x` produced.
+        let opts = ConvOpts::new().dilation(2);
+        let x = randn(&[1, 2, 5, 4], 60);
+        let w = randn(&[2, 3, 3, 3], 61);
+        let deconv = |x: &Var, w: &Var| weighted(conv_transpose2d(x, w, None, opts, 1, 1), 62);
+        let y = conv_transpose2d(
+            &Variable::new(x.clone()),
+            &Variable::new(w.clone()),
+            None,
+            opts,
+            1,
+            1,
+        );
+        assert_eq!(y.shape(), vec![1, 3, 10, 9]);
+        let wv = Variable::new(w.clone());
+        assert!(gradient_check(&|x: &Var| deconv(x, &wv), &x, 1e-5, 1e-7));
+        let xv = Variable::new(x.clone());
+        assert!(gradient_check(&|w: &Var| deconv(&xv, w), &w, 1e-5, 1e-7));
+    }
+
+    /// Gradient that `max_pool2d` sends back to a `[1, 1, H, W]` input when
+    /// its output is weighted by `gy`.
+    fn max_pool_grad(x: &[&[f64]], kernel: usize, opts: ConvOpts, gy: ArrayD<f64>) -> ArrayD<f64> {
+        let (h, w) = (x.len(), x[0].len());
+        let x = ArrayD::from_shape_vec(vec![1, 1, h, w], x.concat()).unwrap();
+        let x = Variable::new(x);
+        let y = max_pool2d(&x, kernel, opts);
+        let gy = gy.into_shape(y.shape()).unwrap();
+        crate::functions::sum(&crate::functions::mul(&y, &Variable::new(gy))).backward();
+        let g = x.grad().unwrap();
+        g.into_shape(vec![h, w]).unwrap()
+    }
+
+    #[test]
+    fn max_pool2d_routes_gradient_to_first_max() {
+        let x: [&[f64]; 4] = [
+            &[1.0, 5.0, 2.0, 2.0],
+            &[3.0, 5.0, 0.0, 2.0],
+            &[-1.0, -2.0, 7.0, 7.0],
+            &[-3.0, -4.0, 7.0, 1.0],
+        ];
+        let gy = ndarray::arr1(&[1.0, 2.0, 3.0, 4.0]).into_dyn();
+        let g = max_pool_grad(&x, 2, ConvOpts::new().stride(2), gy);
+        let expected = ndarray::arr2(&[
+            [0.0, 1.0, 2.0, 0.0],
+            [0.0, 0.0, 0.0, 0.0],
+            [3.0, 0.0, 4.0, 0.0],
+            [0.0, 0.0, 0.0, 0.0],
+        ]);
+        assert_eq!(g, expected.into_dyn());
+    }
+
+    #[test]
+    fn max_pool2d_ignores_padding_and_accumulates_overlaps() {
+        let x: [&[f64]; 2] = [&[-1.0, -2.0], &[-3.0, -4.0]];
+        let opts = ConvOpts::new().pad(1);
+        let y = max_pool2d(
+            &Variable::new(ArrayD::from_shape_vec(vec![1, 1, 2, 2], x.concat()).unwrap()),
+            2,
+            opts,
+        );
+        let expected = [-1.0, -1.0, -2.0, -1.0, -1.0, -2.0, -3.0, -3.0, -4.0];
+        assert_eq!(y.data().iter().copied().collect::<Vec<_>>(), expected);
+        let g = max_pool_grad(&x, 2, opts, ArrayD::ones(vec![9]));
+        assert_eq!(g, ndarray::arr2(&[[4.0, 2.0], [2.0, 1.0]]).into_dyn());
+    }
+
+    #[test]
+    fn avg_pool2d_gradients_with_padding() {
+        let x = randn(&[2, 2, 5, 5], 70);
+        let opts = ConvOpts::new().stride(2).pad(1);
+        let f = |x: &Var| weighted(avg_pool2d(x, 3, opts), 71);
+        assert!(gradient_check(&f, &x, 1e-5, 1e-7));
+    }
+
+    #[test]
+    fn adaptive_avg_pool2d_gradients_with_uneven_bins() {
+        let x = randn(&[2, 3, 5, 7], 72);
+        let f = |x: &Var| weighted(adaptive_avg_pool2d(x, (3, 4)), 73);
+        assert!(gradient_check(&f, &x, 1e-5, 1e-7));
+    }
+}
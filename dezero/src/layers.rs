@@ -0,0 +1,1376 @@
+use ndarray::{Array1, Array2, ArrayD};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::core::{Parameter, Variable, VariableExt};
+use crate::functions as F;
+use crate::functions_conv::{Conv1dOpts, ConvOpts, IntoPair, UpsampleMode};
+use crate::npy;
+use crate::random;
+use crate::safetensors;
+
+/// A building block that owns parameters and possibly other layers.
+///
+/// Implementors list their own parameters in `own_params` and their children
+/// in `sublayers`; `params` and `named_params` walk the whole tree.
+pub trait Layer {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>>;
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        Vec::new()
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        Vec::new()
+    }
+
+    /// Parameters keyed by their path in the layer tree, e.g. `l0/W`.
+    fn named_params(&self) -> Vec<(String, Parameter)> {
+        let mut params = self.own_params();
+        for (name, layer) in self.sublayers() {
+            for (key, p) in layer.named_params() {
+                params.push((format!("{}/{}", name, key), p));
+            }
+        }
+        params
+    }
+
+    fn params(&self) -> std::vec::IntoIter<Parameter> {
+        self.named_params()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn cleargrads(&self) {
+        for p in self.params() {
+            p.cleargrad();
+        }
+    }
+
+    /// Drops any state carried between calls, e.g. a recurrent hidden state.
+    fn reset_state(&mut self) {}
+
+    /// Saves initialized parameters to an `.npz` archive keyed by
+    /// `named_params` paths, the layout DeZero's `save_weights` uses.
+    fn save_weights(&self, path: &Path) -> io::Result<()> {
+        let arrays: Vec<(String, ArrayD<f64>)> = self
+            .named_params()
+            .into_iter()
+            .filter(|(_, p)| p.is_init())
+            .map(|(name, p)| (name, p.data()))
+            .collect();
+        npy::save_npz(path, &arrays)
+    }
+
+    /// Loads parameters saved by `save_weights` (or DeZero). Every
+    /// initialized parameter must be present. Uninitialized ones take the
+    /// stored shape, or stay uninitialized if the archive has none, which is
+    /// how `save_weights` wrote them. Nothing is modified unless the whole
+    /// archive matches.
+    fn load_weights(&self, path: &Path) -> io::Result<()> {
+        assign_params(&self.named_params(), npy::load_npz(path)?, path)
+    }
+
+    /// Like `save_weights`, but writes a safetensors file of `F64` tensors.
+    fn save_safetensors(&self, path: &Path) -> io::Result<()> {
+        let arrays: Vec<(String, ArrayD<f64>)> = self
+            .named_params()
+            .into_iter()
+            .filter(|(_, p)| p.is_init())
+            .map(|(name, p)| (name, p.data()))
+            .collect();
+        safetensors::save_safetensors(path, &arrays, safetensors::Dtype::F64)
+    }
+
+    /// Loads a safetensors file (any supported dtype) with the same rules as
+    /// `load_weights`. The file is memory-mapped, so only the tensors that
+    /// are actually parameters get decoded.
+    fn load_safetensors(&self, path: &Path) -> io::Result<()> {
+        let st = safetensors::SafeTensors::open(path)?;
+        let params = self.named_params();
+        let arrays = params
+            .iter()
+            .filter_map(|(name, _)| st.tensor(name).map(|t| (name.clone(), t.to_array())))
+            .collect();
+        assign_params(&params, arrays, path)
+    }
+}
+
+/// Checks every parameter against `arrays` first and only then assigns, so a
+/// mismatched file leaves the layer untouched. Uninitialized parameters with
+/// no entry are skipped.
+pub(crate) fn assign_params(
+    params: &[(String, Parameter)],
+    arrays: Vec<(String, ArrayD<f64>)>,
+    path: &Path,
+) -> io::Result<()> {
+    let mut arrays: HashMap<String, ArrayD<f64>> = arrays.into_iter().collect();
+    for (name, p) in params {
+        if !p.is_init() && !arrays.contains_key(name) {
+            continue;
+        }
+        let data = arrays.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no array for parameter '{}'", path.display(), name),
+            )
+        })?;
+        if p.is_init() && p.borrow().shape() != data.shape() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "parameter '{}' has shape {:?} but {} holds {:?}",
+                    name,
+                    p.borrow().shape(),
+                    path.display(),
+                    data.shape()
+                ),
+            ));
+        }
+    }
+    for (name, p) in params {
+        if let Some(data) = arrays.remove(name) {
+            p.borrow_mut().data = data;
+        }
+    }
+    Ok(())
+}
+
+/// `own_params` of a layer with a weight `W` and an optional bias `b`.
+fn weight_and_bias(w: &Parameter, b: Option<&Parameter>) -> Vec<(String, Parameter)> {
+    let mut params = vec![("W".to_string(), w.clone())];
+    if let Some(b) = b {
+        params.push(("b".to_string(), b.clone()));
+    }
+    params
+}
+
+/// Fully connected layer over the last axis of its input. When no input
+/// size is given, `W` is created from the feature dimension of the first
+/// batch it sees.
+pub struct Linear {
+    pub in_size: Option<usize>,
+    pub out_size: usize,
+    pub w: Parameter,
+    pub b: Option<Parameter>,
+}
+
+impl Linear {
+    pub fn new(out_size: usize) -> Self {
+        Linear {
+            in_size: None,
+            out_size,
+            w: Parameter::uninit("W"),
+            b: Some(Parameter::new(Array1::<f64>::zeros(out_size), "b")),
+        }
+    }
+
+    pub fn with_in_size(in_size: usize, out_size: usize) -> Self {
+        let mut linear = Linear::new(out_size);
+        linear.init_w(in_size);
+        linear
+    }
+
+    pub fn no_bias(mut self) -> Self {
+        self.b = None;
+        self
+    }
+
+    fn init_w(&mut self, in_size: usize) {
+        self.in_size = Some(in_size);
+        let scale = (1.0 / in_size as f64).sqrt();
+        let w: ArrayD<f64> = random::randn(&[in_size, self.out_size]) * scale;
+        self.w.borrow_mut().data = w;
+    }
+}
+
+impl Layer for Linear {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.w.is_init() {
+            let in_size = *x.borrow().shape().last().unwrap();
+            self.init_w(in_size);
+        }
+        F::linear(x, &self.w, self.b.as_deref())
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        weight_and_bias(&self.w, self.b.as_ref())
+    }
+}
+
+/// Lookup table of `vocab_size` learned vectors of size `embed_size`,
+/// indexed by the integer ids in the input. `W` gets a row-sparse gradient
+/// (see `F::embed_id`), so a step only touches the rows that were used.
+pub struct Embedding {
+    pub w: Parameter,
+}
+
+impl Embedding {
+    pub fn new(vocab_size: usize, embed_size: usize) -> Self {
+        Embedding {
+            w: Parameter::new(random::randn(&[vocab_size, embed_size]), "W"),
+        }
+    }
+}
+
+impl Layer for Embedding {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::embed_id(x, &self.w)
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        vec![("W".to_string(), self.w.clone())]
+    }
+}
+
+/// Parameterless layer wrapping `F::relu`.
+pub struct ReLU;
+
+impl Layer for ReLU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::relu(x)
+    }
+}
+
+pub struct LeakyReLU {
+    pub slope: f64,
+}
+
+impl LeakyReLU {
+    pub fn new(slope: f64) -> Self {
+        LeakyReLU { slope }
+    }
+}
+
+impl Layer for LeakyReLU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::leaky_relu(x, self.slope)
+    }
+}
+
+pub struct ELU {
+    pub alpha: f64,
+}
+
+impl ELU {
+    pub fn new(alpha: f64) -> Self {
+        ELU { alpha }
+    }
+}
+
+impl Layer for ELU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::elu(x, self.alpha)
+    }
+}
+
+pub struct GELU;
+
+impl Layer for GELU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::gelu(x)
+    }
+}
+
+pub struct SiLU;
+
+impl Layer for SiLU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::silu(x)
+    }
+}
+
+pub struct Softplus;
+
+impl Layer for Softplus {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::softplus(x)
+    }
+}
+
+pub struct Sigmoid;
+
+impl Layer for Sigmoid {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::sigmoid(x)
+    }
+}
+
+pub struct Tanh;
+
+impl Layer for Tanh {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::tanh(x)
+    }
+}
+
+/// Dropout as a layer, drawing its masks from the global generator so that
+/// `random::seed` makes it reproducible and `Checkpoint` captures its state.
+pub struct Dropout {
+    pub ratio: f64,
+}
+
+impl Dropout {
+    pub fn new(ratio: f64) -> Self {
+        Dropout { ratio }
+    }
+}
+
+impl Layer for Dropout {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::dropout(x, self.ratio)
+    }
+}
+
+/// Batch normalization over the channel axis 1. `gamma`, `beta` and the
+/// running statistics are sized from the first batch; the running
+/// statistics are parameters too (they never get a gradient), so they are
+/// saved and loaded with the weights.
+pub struct BatchNorm {
+    pub gamma: Parameter,
+    pub beta: Parameter,
+    pub avg_mean: Parameter,
+    pub avg_var: Parameter,
+    pub decay: f64,
+    pub eps: f64,
+}
+
+impl BatchNorm {
+    pub fn new() -> Self {
+        BatchNorm {
+            gamma: Parameter::uninit("gamma"),
+            beta: Parameter::uninit("beta"),
+            avg_mean: Parameter::uninit("avg_mean"),
+            avg_var: Parameter::uninit("avg_var"),
+            decay: 0.9,
+            eps: 2e-5,
+        }
+    }
+}
+
+impl Default for BatchNorm {
+    fn default() -> Self {
+        BatchNorm::new()
+    }
+}
+
+impl Layer for BatchNorm {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.gamma.is_init() {
+            let c = x.borrow().shape()[1];
+            self.gamma.borrow_mut().data = Array1::ones(c).into_dyn();
+            self.beta.borrow_mut().data = Array1::zeros(c).into_dyn();
+            self.avg_mean.borrow_mut().data = Array1::zeros(c).into_dyn();
+            self.avg_var.borrow_mut().data = Array1::ones(c).into_dyn();
+        }
+        F::batch_norm(
+            x,
+            &self.gamma,
+            &self.beta,
+            &self.avg_mean,
+            &self.avg_var,
+            self.decay,
+            self.eps,
+        )
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        vec![
+            ("gamma".to_string(), self.gamma.clone()),
+            ("beta".to_string(), self.beta.clone()),
+            ("avg_mean".to_string(), self.avg_mean.clone()),
+            ("avg_var".to_string(), self.avg_var.clone()),
+        ]
+    }
+}
+
+/// Layer normalization over the trailing axes given by `normalized_shape`.
+pub struct LayerNorm {
+    pub gamma: Parameter,
+    pub beta: Parameter,
+    pub eps: f64,
+}
+
+impl LayerNorm {
+    pub fn new(normalized_shape: &[usize]) -> Self {
+        LayerNorm {
+            gamma: Parameter::new(ArrayD::ones(normalized_shape), "gamma"),
+            beta: Parameter::new(ArrayD::zeros(normalized_shape), "beta"),
+            eps: 1e-5,
+        }
+    }
+}
+
+impl Layer for LayerNorm {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::layer_norm(x, &self.gamma, &self.beta, self.eps)
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        vec![
+            ("gamma".to_string(), self.gamma.clone()),
+            ("beta".to_string(), self.beta.clone()),
+        ]
+    }
+}
+
+/// Group normalization with per-channel `gamma` and `beta`, sized from the
+/// first batch. The channel count must be divisible by `groups`.
+pub struct GroupNorm {
+    pub groups: usize,
+    pub gamma: Parameter,
+    pub beta: Parameter,
+    pub eps: f64,
+}
+
+impl GroupNorm {
+    pub fn new(groups: usize) -> Self {
+        GroupNorm {
+            groups,
+            gamma: Parameter::uninit("gamma"),
+            beta: Parameter::uninit("beta"),
+            eps: 1e-5,
+        }
+    }
+}
+
+impl Layer for GroupNorm {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.gamma.is_init() {
+            let c = x.borrow().shape()[1];
+            self.gamma.borrow_mut().data = Array1::ones(c).into_dyn();
+            self.beta.borrow_mut().data = Array1::zeros(c).into_dyn();
+        }
+        F::group_norm(x, &self.gamma, &self.beta, self.groups, self.eps)
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        vec![
+            ("gamma".to_string(), self.gamma.clone()),
+            ("beta".to_string(), self.beta.clone()),
+        ]
+    }
+}
+
+/// Panics unless `channels` (`which` is "input" or "output") split evenly
+/// into `groups`.
+fn check_groups(channels: usize, groups: usize, which: &str) {
+    assert!(
+        channels % groups == 0,
+        "{} {} channels cannot be split into {} groups",
+        channels,
+        which,
+        groups
+    );
+}
+
+/// 2-D convolution. Like `Linear`, the input channel count may be left to
+/// the first batch, which is when `W` gets created.
+pub struct Conv2d {
+    pub in_channels: Option<usize>,
+    pub out_channels: usize,
+    pub kernel_size: (usize, usize),
+    pub opts: ConvOpts,
+    pub groups: usize,
+    pub w: Parameter,
+    pub b: Option<Parameter>,
+}
+
+impl Conv2d {
+    pub fn new(out_channels: usize, kernel_size: impl IntoPair) -> Self {
+        Conv2d {
+            in_channels: None,
+            out_channels,
+            kernel_size: kernel_size.into_pair(),
+            opts: ConvOpts::default(),
+            groups: 1,
+            w: Parameter::uninit("W"),
+            b: Some(Parameter::new(Array1::<f64>::zeros(out_channels), "b")),
+        }
+    }
+
+    pub fn with_in_channels(mut self, in_channels: usize) -> Self {
+        self.init_w(in_channels);
+        self
+    }
+
+    pub fn stride(mut self, stride: impl IntoPair) -> Self {
+        self.opts = self.opts.stride(stride);
+        self
+    }
+
+    pub fn pad(mut self, pad: impl IntoPair) -> Self {
+        self.opts = self.opts.pad(pad);
+        self
+    }
+
+    pub fn dilation(mut self, dilation: impl IntoPair) -> Self {
+        self.opts = self.opts.dilation(dilation);
+        self
+    }
+
+    /// Splits channels into `groups` independent convolutions. Set this
+    /// before `with_in_channels`, since it changes the shape of `W`.
+    pub fn groups(mut self, groups: usize) -> Self {
+        check_groups(self.out_channels, groups, "output");
+        self.groups = groups;
+        self
+    }
+
+    pub fn no_bias(mut self) -> Self {
+        self.b = None;
+        self
+    }
+
+    fn init_w(&mut self, in_channels: usize) {
+        check_groups(in_channels, self.groups, "input");
+        self.in_channels = Some(in_channels);
+        let (kh, kw) = self.kernel_size;
+        let c = in_channels / self.groups;
+        let scale = (1.0 / (c * kh * kw) as f64).sqrt();
+        let w: ArrayD<f64> = random::randn(&[self.out_channels, c, kh, kw]) * scale;
+        self.w.borrow_mut().data = w;
+    }
+}
+
+impl Layer for Conv2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.w.is_init() {
+            let in_channels = x.borrow().shape()[1];
+            self.init_w(in_channels);
+        }
+        F::conv2d(x, &self.w, self.b.as_deref(), self.opts, self.groups)
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        weight_and_bias(&self.w, self.b.as_ref())
+    }
+}
+
+/// Max pooling layer. Unlike `F::max_pool2d`, the stride defaults to the
+/// kernel size, so windows do not overlap.
+pub struct MaxPool2d {
+    pub kernel_size: (usize, usize),
+    pub opts: ConvOpts,
+}
+
+impl MaxPool2d {
+    pub fn new(kernel_size: impl IntoPair) -> Self {
+        let kernel_size = kernel_size.into_pair();
+        MaxPool2d {
+            kernel_size,
+            opts: ConvOpts::new().stride(kernel_size),
+        }
+    }
+
+    pub fn stride(mut self, stride: impl IntoPair) -> Self {
+        self.opts = self.opts.stride(stride);
+        self
+    }
+
+    pub fn pad(mut self, pad: impl IntoPair) -> Self {
+        self.opts = self.opts.pad(pad);
+        self
+    }
+}
+
+impl Layer for MaxPool2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::max_pool2d(x, self.kernel_size, self.opts)
+    }
+}
+
+/// Average pooling layer; the stride defaults to the kernel size.
+pub struct AvgPool2d {
+    pub kernel_size: (usize, usize),
+    pub opts: ConvOpts,
+}
+
+impl AvgPool2d {
+    pub fn new(kernel_size: impl IntoPair) -> Self {
+        let kernel_size = kernel_size.into_pair();
+        AvgPool2d {
+            kernel_size,
+            opts: ConvOpts::new().stride(kernel_size),
+        }
+    }
+
+    pub fn stride(mut self, stride: impl IntoPair) -> Self {
+        self.opts = self.opts.stride(stride);
+        self
+    }
+
+    pub fn pad(mut self, pad: impl IntoPair) -> Self {
+        self.opts = self.opts.pad(pad);
+        self
+    }
+}
+
+impl Layer for AvgPool2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::avg_pool2d(x, self.kernel_size, self.opts)
+    }
+}
+
+pub struct AdaptiveAvgPool2d {
+    pub output_size: (usize, usize),
+}
+
+impl AdaptiveAvgPool2d {
+    pub fn new(output_size: impl IntoPair) -> Self {
+        AdaptiveAvgPool2d {
+            output_size: output_size.into_pair(),
+        }
+    }
+}
+
+impl Layer for AdaptiveAvgPool2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::adaptive_avg_pool2d(x, self.output_size)
+    }
+}
+
+/// `[N, C, H, W]` to `[N, C]` by averaging over the spatial axes.
+pub struct GlobalAvgPool2d;
+
+impl Layer for GlobalAvgPool2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::global_avg_pool2d(x)
+    }
+}
+
+/// Transposed 2-D convolution (`F::conv_transpose2d`). `W` is
+/// `[C, OC / groups, KH, KW]` and, as in `Conv2d`, may wait for the first
+/// batch to learn `C`.
+pub struct Deconv2d {
+    pub in_channels: Option<usize>,
+    pub out_channels: usize,
+    pub kernel_size: (usize, usize),
+    pub opts: ConvOpts,
+    pub groups: usize,
+    pub output_padding: (usize, usize),
+    pub w: Parameter,
+    pub b: Option<Parameter>,
+}
+
+impl Deconv2d {
+    pub fn new(out_channels: usize, kernel_size: impl IntoPair) -> Self {
+        Deconv2d {
+            in_channels: None,
+            out_channels,
+            kernel_size: kernel_size.into_pair(),
+            opts: ConvOpts::default(),
+            groups: 1,
+            output_padding: (0, 0),
+            w: Parameter::uninit("W"),
+            b: Some(Parameter::new(Array1::<f64>::zeros(out_channels), "b")),
+        }
+    }
+
+    pub fn with_in_channels(mut self, in_channels: usize) -> Self {
+        self.init_w(in_channels);
+        self
+    }
+
+    pub fn stride(mut self, stride: impl IntoPair) -> Self {
+        self.opts = self.opts.stride(stride);
+        self
+    }
+
+    pub fn pad(mut self, pad: impl IntoPair) -> Self {
+        self.opts = self.opts.pad(pad);
+        self
+    }
+
+    pub fn dilation(mut self, dilation: impl IntoPair) -> Self {
+        self.opts = self.opts.dilation(dilation);
+        self
+    }
+
+    pub fn output_padding(mut self, output_padding: impl IntoPair) -> Self {
+        self.output_padding = output_padding.into_pair();
+        self
+    }
+
+    /// As `Conv2d::groups`.
+    pub fn groups(mut self, groups: usize) -> Self {
+        check_groups(self.out_channels, groups, "output");
+        self.groups = groups;
+        self
+    }
+
+    pub fn no_bias(mut self) -> Self {
+        self.b = None;
+        self
+    }
+
+    fn init_w(&mut self, in_channels: usize) {
+        check_groups(in_channels, self.groups, "input");
+        self.in_channels = Some(in_channels);
+        let (kh, kw) = self.kernel_size;
+        let oc = self.out_channels / self.groups;
+        let scale = (1.0 / (in_channels * kh * kw) as f64).sqrt();
+        let w: ArrayD<f64> = random::randn(&[in_channels, oc, kh, kw]) * scale;
+        self.w.borrow_mut().data = w;
+    }
+}
+
+impl Layer for Deconv2d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.w.is_init() {
+            let in_channels = x.borrow().shape()[1];
+            self.init_w(in_channels);
+        }
+        F::conv_transpose2d(
+            x,
+            &self.w,
+            self.b.as_deref(),
+            self.opts,
+            self.groups,
+            self.output_padding,
+        )
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        weight_and_bias(&self.w, self.b.as_ref())
+    }
+}
+
+pub struct Upsample {
+    pub scale: (usize, usize),
+    pub mode: UpsampleMode,
+}
+
+impl Upsample {
+    pub fn new(scale: impl IntoPair, mode: UpsampleMode) -> Self {
+        Upsample {
+            scale: scale.into_pair(),
+            mode,
+        }
+    }
+}
+
+impl Layer for Upsample {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        F::upsample(x, self.scale, self.mode)
+    }
+}
+
+/// 1-D convolution over `[N, C, L]` sequences, optionally causal. `W` is
+/// `[OC, C / groups, K]`, created from the first batch unless
+/// `with_in_channels` is used.
+pub struct Conv1d {
+    pub in_channels: Option<usize>,
+    pub out_channels: usize,
+    pub kernel_size: usize,
+    pub opts: Conv1dOpts,
+    pub groups: usize,
+    pub w: Parameter,
+    pub b: Option<Parameter>,
+}
+
+impl Conv1d {
+    pub fn new(out_channels: usize, kernel_size: usize) -> Self {
+        Conv1d {
+            in_channels: None,
+            out_channels,
+            kernel_size,
+            opts: Conv1dOpts::default(),
+            groups: 1,
+            w: Parameter::uninit("W"),
+            b: Some(Parameter::new(Array1::<f64>::zeros(out_channels), "b")),
+        }
+    }
+
+    pub fn with_in_channels(mut self, in_channels: usize) -> Self {
+        self.init_w(in_channels);
+        self
+    }
+
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.opts = self.opts.stride(stride);
+        self
+    }
+
+    pub fn pad(mut self, pad: usize) -> Self {
+        self.opts = self.opts.pad(pad);
+        self
+    }
+
+    pub fn dilation(mut self, dilation: usize) -> Self {
+        self.opts = self.opts.dilation(dilation);
+        self
+    }
+
+    /// Pads on the left only, so no output sees future input.
+    pub fn causal(mut self) -> Self {
+        self.opts = self.opts.causal(true);
+        self
+    }
+
+    /// As `Conv2d::groups`.
+    pub fn groups(mut self, groups: usize) -> Self {
+        check_groups(self.out_channels, groups, "output");
+        self.groups = groups;
+        self
+    }
+
+    pub fn no_bias(mut self) -> Self {
+        self.b = None;
+        self
+    }
+
+    fn init_w(&mut self, in_channels: usize) {
+        check_groups(in_channels, self.groups, "input");
+        self.in_channels = Some(in_channels);
+        let c = in_channels / self.groups;
+        let scale = (1.0 / (c * self.kernel_size) as f64).sqrt();
+        let w: ArrayD<f64> = random::randn(&[self.out_channels, c, self.kernel_size]) * scale;
+        self.w.borrow_mut().data = w;
+    }
+}
+
+impl Layer for Conv1d {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        if !self.w.is_init() {
+            let in_channels = x.borrow().shape()[1];
+            self.init_w(in_channels);
+        }
+        F::conv1d(x, &self.w, self.b.as_deref(), self.opts, self.groups)
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        weight_and_bias(&self.w, self.b.as_ref())
+    }
+}
+
+/// Elman RNN cell, `h = tanh(x W_x + b + h W_h)`. The hidden state is kept
+/// between calls until `reset_state`; the first step after a reset skips
+/// the `h W_h` term.
+pub struct RNN {
+    pub x2h: Linear,
+    pub h2h: Linear,
+    pub h: Option<Rc<RefCell<Variable>>>,
+}
+
+impl RNN {
+    pub fn new(hidden_size: usize) -> Self {
+        RNN {
+            x2h: Linear::new(hidden_size),
+            h2h: Linear::with_in_size(hidden_size, hidden_size).no_bias(),
+            h: None,
+        }
+    }
+}
+
+impl Layer for RNN {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let a = self.x2h.forward(x);
+        let a = match &self.h {
+            Some(h) => F::add(&a, &self.h2h.forward(h)),
+            None => a,
+        };
+        let h = F::tanh(&a);
+        self.h = Some(h.clone());
+        h
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        vec![
+            ("x2h".to_string(), &self.x2h as &dyn Layer),
+            ("h2h".to_string(), &self.h2h),
+        ]
+    }
+
+    fn reset_state(&mut self) {
+        self.h = None;
+    }
+}
+
+/// `x` sliced into the `i`-th of equal blocks of `size` columns.
+fn gate(x: &Rc<RefCell<Variable>>, i: usize, size: usize) -> Rc<RefCell<Variable>> {
+    F::slice_axis(x, 1, i * size..(i + 1) * size)
+}
+
+/// LSTM with the input, forget, output and candidate projections fused
+/// into one `Linear` each for `x` and `h` (gates in that order). Keeps `h`
+/// and the cell `c` between calls until `reset_state`.
+pub struct LSTM {
+    pub hidden_size: usize,
+    pub x2h: Linear,
+    pub h2h: Linear,
+    pub h: Option<Rc<RefCell<Variable>>>,
+    pub c: Option<Rc<RefCell<Variable>>>,
+}
+
+impl LSTM {
+    pub fn new(hidden_size: usize) -> Self {
+        LSTM {
+            hidden_size,
+            x2h: Linear::new(4 * hidden_size),
+            h2h: Linear::with_in_size(hidden_size, 4 * hidden_size).no_bias(),
+            h: None,
+            c: None,
+        }
+    }
+}
+
+impl Layer for LSTM {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let a = self.x2h.forward(x);
+        let a = match &self.h {
+            Some(h) => F::add(&a, &self.h2h.forward(h)),
+            None => a,
+        };
+        let n = self.hidden_size;
+        let i = F::sigmoid(&gate(&a, 0, n));
+        let f = F::sigmoid(&gate(&a, 1, n));
+        let o = F::sigmoid(&gate(&a, 2, n));
+        let u = F::tanh(&gate(&a, 3, n));
+        let c = match &self.c {
+            Some(c) => F::add(&F::mul(&f, c), &F::mul(&i, &u)),
+            None => F::mul(&i, &u),
+        };
+        let h = F::mul(&o, &F::tanh(&c));
+        self.h = Some(h.clone());
+        self.c = Some(c);
+        h
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        vec![
+            ("x2h".to_string(), &self.x2h as &dyn Layer),
+            ("h2h".to_string(), &self.h2h),
+        ]
+    }
+
+    fn reset_state(&mut self) {
+        self.h = None;
+        self.c = None;
+    }
+}
+
+/// GRU with fused reset, update and candidate projections:
+/// `n = tanh(x W_xn + b_xn + r * (h W_hn + b_hn))` and
+/// `h' = (1 - z) * n + z * h`. The state starts at zeros after a reset.
+pub struct GRU {
+    pub hidden_size: usize,
+    pub x2h: Linear,
+    pub h2h: Linear,
+    pub h: Option<Rc<RefCell<Variable>>>,
+}
+
+impl GRU {
+    pub fn new(hidden_size: usize) -> Self {
+        GRU {
+            hidden_size,
+            x2h: Linear::new(3 * hidden_size),
+            h2h: Linear::with_in_size(hidden_size, 3 * hidden_size),
+            h: None,
+        }
+    }
+}
+
+impl Layer for GRU {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let n = self.hidden_size;
+        let h = match &self.h {
+            Some(h) => h.clone(),
+            None => Variable::new(Array2::<f64>::zeros((x.borrow().shape()[0], n))),
+        };
+        let ax = self.x2h.forward(x);
+        let ah = self.h2h.forward(&h);
+        let r = F::sigmoid(&F::add(&gate(&ax, 0, n), &gate(&ah, 0, n)));
+        let z = F::sigmoid(&F::add(&gate(&ax, 1, n), &gate(&ah, 1, n)));
+        let cand = F::tanh(&F::add(&gate(&ax, 2, n), &F::mul(&r, &gate(&ah, 2, n))));
+        let one_minus_z = F::sub(&F::scalar(1.0), &z);
+        let h = F::add(&F::mul(&one_minus_z, &cand), &F::mul(&z, &h));
+        self.h = Some(h.clone());
+        h
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        vec![
+            ("x2h".to_string(), &self.x2h as &dyn Layer),
+            ("h2h".to_string(), &self.h2h),
+        ]
+    }
+
+    fn reset_state(&mut self) {
+        self.h = None;
+    }
+}
+
+/// `dropout` applied to `x`, skipped entirely when its ratio is zero.
+fn maybe_dropout(dropout: &mut Dropout, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+    if dropout.ratio > 0.0 {
+        dropout.forward(x)
+    } else {
+        x.clone()
+    }
+}
+
+/// `[N, T, E]` to `[N, heads, T, E / heads]`.
+fn split_heads(x: &Rc<RefCell<Variable>>, heads: usize) -> Rc<RefCell<Variable>> {
+    let shape = x.borrow().shape().to_vec();
+    let (n, t, e) = (shape[0], shape[1], shape[2]);
+    let x = F::reshape(x, &[n, t, heads, e / heads]);
+    F::transpose_axes(&x, &[0, 2, 1, 3])
+}
+
+/// Multi-head attention over `[N, T, embed_size]` inputs. Queries, keys and
+/// values are projected, split into `num_heads` heads of
+/// `embed_size / num_heads` features, attended to separately with
+/// `F::scaled_dot_product_attention` and merged by an output projection.
+/// `forward` is self-attention; `attend` takes separate inputs and a mask.
+pub struct MultiHeadAttention {
+    pub num_heads: usize,
+    pub wq: Linear,
+    pub wk: Linear,
+    pub wv: Linear,
+    pub wo: Linear,
+    /// Applied to the attention weights in train mode.
+    pub dropout: Dropout,
+    /// Masks out future positions in `forward`.
+    pub causal: bool,
+}
+
+impl MultiHeadAttention {
+    pub fn new(embed_size: usize, num_heads: usize) -> Self {
+        assert!(
+            embed_size % num_heads == 0,
+            "embed_size must be divisible by num_heads"
+        );
+        MultiHeadAttention {
+            num_heads,
+            wq: Linear::with_in_size(embed_size, embed_size),
+            wk: Linear::with_in_size(embed_size, embed_size),
+            wv: Linear::with_in_size(embed_size, embed_size),
+            wo: Linear::with_in_size(embed_size, embed_size),
+            dropout: Dropout::new(0.0),
+            causal: false,
+        }
+    }
+
+    pub fn dropout(mut self, ratio: f64) -> Self {
+        self.dropout.ratio = ratio;
+        self
+    }
+
+    pub fn causal(mut self) -> Self {
+        self.causal = true;
+        self
+    }
+
+    /// Attends from `q` (`[N, Tq, E]`) to `k` and `v` (`[N, Tk, E]`).
+    /// `mask` broadcasts against the `[N, heads, Tq, Tk]` scores, so it can
+    /// be `[Tq, Tk]` for a shared pattern or `[N, 1, 1, Tk]` for padding; a
+    /// zero entry blocks that query from that key.
+    pub fn attend(
+        &mut self,
+        q: &Rc<RefCell<Variable>>,
+        k: &Rc<RefCell<Variable>>,
+        v: &Rc<RefCell<Variable>>,
+        mask: Option<&ArrayD<f64>>,
+    ) -> Rc<RefCell<Variable>> {
+        let h = self.num_heads;
+        let q = split_heads(&self.wq.forward(q), h);
+        let k = split_heads(&self.wk.forward(k), h);
+        let v = split_heads(&self.wv.forward(v), h);
+        let weights = F::attention_weights(&q, &k, mask);
+        let weights = maybe_dropout(&mut self.dropout, &weights);
+        let y = F::transpose_axes(&F::matmul(&weights, &v), &[0, 2, 1, 3]);
+        let shape = y.borrow().shape().to_vec();
+        let y = F::reshape(&y, &[shape[0], shape[1], shape[2] * shape[3]]);
+        self.wo.forward(&y)
+    }
+
+    /// Self-attention with an optional mask, combined with the causal mask
+    /// when `causal` is set.
+    pub fn forward_with_mask(
+        &mut self,
+        x: &Rc<RefCell<Variable>>,
+        mask: Option<&ArrayD<f64>>,
+    ) -> Rc<RefCell<Variable>> {
+        let mask = if self.causal {
+            let causal = F::causal_mask(x.borrow().shape()[1]);
+            Some(match mask {
+                Some(m) => &causal * m,
+                None => causal,
+            })
+        } else {
+            mask.cloned()
+        };
+        self.attend(x, x, x, mask.as_ref())
+    }
+}
+
+impl Layer for MultiHeadAttention {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        self.forward_with_mask(x, None)
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        vec![
+            ("wq".to_string(), &self.wq as &dyn Layer),
+            ("wk".to_string(), &self.wk),
+            ("wv".to_string(), &self.wv),
+            ("wo".to_string(), &self.wo),
+        ]
+    }
+}
+
+/// The fixed encoding of "Attention Is All You Need":
+/// `pe[pos, 2i] = sin(pos / 10000^(2i / d))` and `pe[pos, 2i + 1]` the
+/// matching cosine, for `max_len` positions and `d = embed_size`.
+pub fn sinusoidal_encoding(max_len: usize, embed_size: usize) -> Array2<f64> {
+    Array2::from_shape_fn((max_len, embed_size), |(pos, j)| {
+        let angle = pos as f64 / 10000f64.powf((j - j % 2) as f64 / embed_size as f64);
+        if j % 2 == 0 {
+            angle.sin()
+        } else {
+            angle.cos()
+        }
+    })
+}
+
+/// Adds `sinusoidal_encoding` to `[N, T, embed_size]` input, `T <= max_len`.
+/// Has no parameters.
+pub struct SinusoidalPositionalEncoding {
+    pub pe: Array2<f64>,
+}
+
+impl SinusoidalPositionalEncoding {
+    pub fn new(max_len: usize, embed_size: usize) -> Self {
+        SinusoidalPositionalEncoding {
+            pe: sinusoidal_encoding(max_len, embed_size),
+        }
+    }
+}
+
+impl Layer for SinusoidalPositionalEncoding {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let t = x.borrow().shape()[1];
+        let pe = self.pe.slice(ndarray::s![..t, ..]).to_owned();
+        F::add(x, &Variable::new(pe))
+    }
+}
+
+/// Adds a learned `[max_len, embed_size]` table `P` to `[N, T, embed_size]`
+/// input, one row per position.
+pub struct LearnedPositionalEncoding {
+    pub p: Parameter,
+}
+
+impl LearnedPositionalEncoding {
+    pub fn new(max_len: usize, embed_size: usize) -> Self {
+        LearnedPositionalEncoding {
+            p: Parameter::new(random::randn(&[max_len, embed_size]), "P"),
+        }
+    }
+}
+
+impl Layer for LearnedPositionalEncoding {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let t = x.borrow().shape()[1];
+        F::add(x, &F::slice_axis(&self.p, 0, 0..t))
+    }
+
+    fn own_params(&self) -> Vec<(String, Parameter)> {
+        vec![("P".to_string(), self.p.clone())]
+    }
+}
+
+/// Transformer encoder block on `[N, T, embed_size]` input: self-attention
+/// then a two-layer ReLU feed-forward network, each wrapped in a residual
+/// connection with dropout and `LayerNorm`. Normalization comes after each
+/// residual by default, as in the original paper, or before each sub-block
+/// with `norm_first`.
+pub struct TransformerEncoderLayer {
+    pub self_attn: MultiHeadAttention,
+    pub linear1: Linear,
+    pub linear2: Linear,
+    pub norm1: LayerNorm,
+    pub norm2: LayerNorm,
+    pub dropout: Dropout,
+    pub norm_first: bool,
+}
+
+impl TransformerEncoderLayer {
+    pub fn new(embed_size: usize, num_heads: usize, ff_size: usize) -> Self {
+        TransformerEncoderLayer {
+            self_attn: MultiHeadAttention::new(embed_size, num_heads).dropout(0.1),
+            linear1: Linear::with_in_size(embed_size, ff_size),
+            linear2: Linear::with_in_size(ff_size, embed_size),
+            norm1: LayerNorm::new(&[embed_size]),
+            norm2: LayerNorm::new(&[embed_size]),
+            dropout: Dropout::new(0.1),
+            norm_first: false,
+        }
+    }
+
+    /// Sets the dropout ratio everywhere in the block, attention included.
+    pub fn dropout(mut self, ratio: f64) -> Self {
+        self.self_attn.dropout.ratio = ratio;
+        self.dropout.ratio = ratio;
+        self
+    }
+
+    pub fn norm_first(mut self) -> Self {
+        self.norm_first = true;
+        self
+    }
+
+    pub fn causal(mut self) -> Self {
+        self.self_attn.causal = true;
+        self
+    }
+
+    fn attention_block(
+        &mut self,
+        x: &Rc<RefCell<Variable>>,
+        mask: Option<&ArrayD<f64>>,
+    ) -> Rc<RefCell<Variable>> {
+        let y = self.self_attn.forward_with_mask(x, mask);
+        maybe_dropout(&mut self.dropout, &y)
+    }
+
+    fn feed_forward_block(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let h = F::relu(&self.linear1.forward(x));
+        let h = maybe_dropout(&mut self.dropout, &h);
+        let y = self.linear2.forward(&h);
+        maybe_dropout(&mut self.dropout, &y)
+    }
+
+    /// `forward` with a mask for the self-attention, as in
+    /// `MultiHeadAttention::attend`.
+    pub fn forward_with_mask(
+        &mut self,
+        x: &Rc<RefCell<Variable>>,
+        mask: Option<&ArrayD<f64>>,
+    ) -> Rc<RefCell<Variable>> {
+        if self.norm_first {
+            let h = self.norm1.forward(x);
+            let x = F::add(x, &self.attention_block(&h, mask));
+            let h = self.norm2.forward(&x);
+            F::add(&x, &self.feed_forward_block(&h))
+        } else {
+            let a = self.attention_block(x, mask);
+            let x = self.norm1.forward(&F::add(x, &a));
+            let f = self.feed_forward_block(&x);
+            self.norm2.forward(&F::add(&x, &f))
+        }
+    }
+}
+
+impl Layer for TransformerEncoderLayer {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        self.forward_with_mask(x, None)
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        vec![
+            ("self_attn".to_string(), &self.self_attn as &dyn Layer),
+            ("linear1".to_string(), &self.linear1),
+            ("linear2".to_string(), &self.linear2),
+            ("norm1".to_string(), &self.norm1),
+            ("norm2".to_string(), &self.norm2),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::MLP;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("dezero-{}-{}", std::process::id(), name))
+    }
+
+    type SaveLoad = fn(&MLP, &Path) -> io::Result<()>;
+
+    fn assert_round_trip(name: &str, save: SaveLoad, load: SaveLoad) {
+        let path = temp_path(name);
+        let mut model = MLP::new(&[4, 2], F::sigmoid);
+        model.forward(&Variable::new(random::randn(&[3, 5])));
+        save(&model, &path).unwrap();
+
+        let mut fresh = MLP::new(&[4, 2], F::sigmoid);
+        load(&fresh, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names: Vec<_> = model.named_params().into_iter().map(|(n, _)| n).collect();
+        let loaded: Vec<_> = fresh.named_params().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, loaded);
+        for ((_, a), (_, b)) in model.named_params().iter().zip(fresh.named_params().iter()) {
+            assert_eq!(a.data(), b.data());
+        }
+        let x = Variable::new(random::randn(&[3, 5]));
+        assert_eq!(model.forward(&x).data(), fresh.forward(&x).data());
+    }
+
+    #[test]
+    fn save_load_weights_round_trip() {
+        assert_round_trip("round_trip.npz", MLP::save_weights, MLP::load_weights);
+    }
+
+    #[test]
+    fn save_load_safetensors_round_trip() {
+        assert_round_trip(
+            "round_trip.safetensors",
+            MLP::save_safetensors,
+            MLP::load_safetensors,
+        );
+    }
+
+    #[test]
+    fn dropout_resumes_from_global_rng_state() {
+        let x = Variable::new(ArrayD::ones(ndarray::IxDyn(&[4, 8])));
+        let mut layer = Dropout::new(0.5);
+        let state = random::get_state();
+        let first = layer.forward(&x).data();
+        random::set_state(state);
+        assert_eq!(Dropout::new(0.5).forward(&x).data(), first);
+    }
+
+    #[test]
+    fn load_weights_saved_before_lazy_init() {
+        let path = temp_path("lazy.npz");
+        let model = MLP::new(&[4, 2], F::sigmoid);
+        model.save_weights(&path).unwrap();
+
+        let fresh = MLP::new(&[4, 2], F::sigmoid);
+        fresh.load_weights(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let w = &fresh.layers[0].w;
+        assert!(!w.is_init());
+        assert_eq!(fresh.layers[0].b.as_ref().unwrap().data().shape(), &[4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "3 input channels cannot be split into 2 groups")]
+    fn deconv2d_rejects_uneven_input_groups() {
+        Deconv2d::new(4, 3).groups(2).with_in_channels(3);
+    }
+
+    fn assert_reset_forgets_state(mut layer: impl Layer, name: &str) {
+        let x = Variable::new(random::randn(&[2, 3]));
+        let first = layer.forward(&x).data();
+        assert_ne!(layer.forward(&x).data(), first, "{} kept no state", name);
+        layer.reset_state();
+        assert_eq!(layer.forward(&x).data(), first, "{}", name);
+    }
+
+    #[test]
+    fn recurrent_reset_state_forgets_hidden_state() {
+        assert_reset_forgets_state(RNN::new(4), "RNN");
+        assert_reset_forgets_state(LSTM::new(4), "LSTM");
+        assert_reset_forgets_state(GRU::new(4), "GRU");
+    }
+}
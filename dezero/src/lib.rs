@@ -0,0 +1,18 @@
+pub mod checkpoint;
+pub mod core;
+pub mod dataloaders;
+pub mod datasets;
+pub mod functions;
+pub mod functions_conv;
+pub mod layers;
+pub mod models;
+pub mod npy;
+pub mod optimizers;
+pub mod random;
+pub mod safetensors;
+pub mod schedulers;
+pub mod transforms;
+pub mod utils;
+
+pub use crate::core::{no_grad, test_mode, Function, Parameter, Variable, VariableExt};
+pub use crate::layers::Layer;
@@ -0,0 +1,49 @@
+use dezero::dataloaders::DataLoader;
+use dezero::datasets::{get_spiral, ArrayDataset};
+use dezero::functions as F;
+use dezero::models::MLP;
+use dezero::optimizers::{Optimizer, SGD};
+use dezero::{random, Layer, Variable, VariableExt};
+
+fn main() {
+    let max_epoch = 300;
+    let batch_size = 30;
+    let hidden_size = 10;
+    let lr = 1.0;
+
+    random::seed(0);
+    let (x, t) = get_spiral(true);
+    let mut train_loader = DataLoader::new(ArrayDataset::new(x, t), batch_size, true);
+    let mut model = MLP::new(&[hidden_size, 3], F::sigmoid);
+    let mut optimizer = SGD::new(lr);
+    optimizer.setup(&model);
+
+    for epoch in 0..max_epoch {
+        let (mut sum_loss, mut sum_acc, mut count) = (0.0, 0.0, 0.0);
+        for (x, t) in &mut train_loader {
+            let y = model.forward(&x);
+            let loss = F::softmax_cross_entropy(&y, &t);
+            model.cleargrads();
+            loss.backward();
+            optimizer.update();
+
+            let n = t.borrow().size() as f64;
+            sum_loss += loss.borrow().data.sum() * n;
+            sum_acc += F::accuracy(&y, &t) * n;
+            count += n;
+        }
+        if epoch % 50 == 0 || epoch == max_epoch - 1 {
+            println!(
+                "epoch {}, loss {:.4}, accuracy {:.4}",
+                epoch + 1,
+                sum_loss / count,
+                sum_acc / count
+            );
+        }
+    }
+
+    let (x, t) = get_spiral(false);
+    let _guard = dezero::no_grad();
+    let y = model.forward(&Variable::new(x));
+    println!("test accuracy {:.4}", F::accuracy(&y, &Variable::new(t)));
+}
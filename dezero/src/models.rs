@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::core::Variable;
+use crate::layers::{Layer, Linear};
+
+pub type Activation = fn(&Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>>;
+
+/// Multi-layer perceptron: one `Linear` per entry of `fc_output_sizes`, with
+/// `activation` applied between them (not after the last one).
+pub struct MLP {
+    pub layers: Vec<Linear>,
+    pub activation: Activation,
+}
+
+impl MLP {
+    pub fn new(fc_output_sizes: &[usize], activation: Activation) -> Self {
+        assert!(
+            !fc_output_sizes.is_empty(),
+            "an MLP needs at least one layer size"
+        );
+        MLP {
+            layers: fc_output_sizes.iter().map(|&s| Linear::new(s)).collect(),
+            activation,
+        }
+    }
+}
+
+impl Layer for MLP {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let last = self.layers.len() - 1;
+        let mut x = x.clone();
+        for (i, l) in self.layers.iter_mut().enumerate() {
+            x = l.forward(&x);
+            if i != last {
+                x = (self.activation)(&x);
+            }
+        }
+        x
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (format!("l{}", i), l as &dyn Layer))
+            .collect()
+    }
+}
+
+/// Runs its layers one after another.
+#[derive(Default)]
+pub struct Sequential {
+    pub layers: Vec<Box<dyn Layer>>,
+}
+
+impl Sequential {
+    pub fn new(layers: Vec<Box<dyn Layer>>) -> Self {
+        Sequential { layers }
+    }
+
+    pub fn push(&mut self, layer: Box<dyn Layer>) {
+        self.layers.push(layer);
+    }
+}
+
+impl Layer for Sequential {
+    fn forward(&mut self, x: &Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>> {
+        let mut x = x.clone();
+        for l in self.layers.iter_mut() {
+            x = l.forward(&x);
+        }
+        x
+    }
+
+    fn sublayers(&self) -> Vec<(String, &dyn Layer)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (format!("l{}", i), l.as_ref()))
+            .collect()
+    }
+
+    fn reset_state(&mut self) {
+        for l in self.layers.iter_mut() {
+            l.reset_state();
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use ndarray::{ArrayD, IxDyn, ShapeBuilder};
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::core::Variable;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn shape_tuple(shape: &[usize]) -> String {
+    match shape {
+        [] => "()".to_string(),
+        [n] => format!("({},)", n),
+        _ => {
+            let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+            format!("({})", dims.join(", "))
+        }
+    }
+}
+
+/// Writes `a` as a version 1.0 `.npy` stream of little-endian `f64` in C order.
+pub fn write_npy<W: Write>(w: &mut W, a: &ArrayD<f64>) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}",
+        shape_tuple(a.shape())
+    );
+    // Pad so the data starts on a 64-byte boundary, as NumPy does.
+    let unpadded = MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    w.write_all(MAGIC)?;
+    w.write_all(&[1, 0])?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    let data: Vec<u8> = a
+        .as_standard_layout()
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    w.write_all(&data)
+}
+
+struct Header {
+    descr: String,
+    fortran_order: bool,
+    shape: Vec<usize>,
+}
+
+/// Pulls `'key': value` out of the Python dict literal in an npy header.
+fn header_field<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let pat = format!("'{}':", key);
+    let start = header
+        .find(&pat)
+        .ok_or_else(|| invalid(format!("npy header has no '{}'", key)))?;
+    Ok(header[start + pat.len()..].trim_start())
+}
+
+fn parse_header(header: &str) -> io::Result<Header> {
+    let descr = header_field(header, "descr")?;
+    let descr = descr
+        .strip_prefix('\'')
+        .and_then(|d| d.split('\'').next())
+        .ok_or_else(|| invalid("malformed npy descr".to_string()))?
+        .to_string();
+
+    let fortran_order = header_field(header, "fortran_order")?.starts_with("True");
+
+    let shape = header_field(header, "shape")?
+        .strip_prefix('(')
+        .and_then(|s| s.find(')').map(|end| &s[..end]))
+        .ok_or_else(|| invalid("malformed npy shape".to_string()))?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<usize>()
+                .map_err(|_| invalid(format!("bad npy dimension '{}'", s)))
+        })
+        .collect::<io::Result<Vec<usize>>>()?;
+    Ok(Header {
+        descr,
+        fortran_order,
+        shape,
+    })
+}
+
+/// Decodes `count` elements of the npy type `descr` (e.g. `<f4`, `>i8`,
+/// `|u1`, `|b1`) into `f64`.
+fn decode<R: Read>(r: &mut R, descr: &str, count: usize) -> io::Result<Vec<f64>> {
+    let unsupported = || invalid(format!("unsupported npy dtype '{}'", descr));
+    let (order, kind, width) = match (descr.get(..1), descr.get(1..2), descr.get(2..)) {
+        (Some(order), Some(kind), Some(width)) => (order, kind, width),
+        _ => return Err(unsupported()),
+    };
+    let big = match order {
+        "<" | "|" => false,
+        ">" => true,
+        "=" => cfg!(target_endian = "big"),
+        _ => return Err(unsupported()),
+    };
+    let width: usize = width.parse().map_err(|_| unsupported())?;
+    let len = count.checked_mul(width).ok_or_else(|| {
+        invalid(format!(
+            "npy data of {} x {} bytes is too large",
+            count, width
+        ))
+    })?;
+    // Read through `take` so a header claiming more data than the stream
+    // holds fails on the short read instead of allocating up front.
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("npy data ends after {} of {} bytes", buf.len(), len),
+        ));
+    }
+
+    macro_rules! conv {
+        ($t:ty) => {
+            buf.chunks_exact(width)
+                .map(|c| {
+                    let b = c.try_into().unwrap();
+                    (if big {
+                        <$t>::from_be_bytes(b)
+                    } else {
+                        <$t>::from_le_bytes(b)
+                    }) as f64
+                })
+                .collect()
+        };
+    }
+    let data = match kind {
+        "f" if width == 4 => conv!(f32),
+        "f" if width == 8 => conv!(f64),
+        "i" if width == 1 => conv!(i8),
+        "i" if width == 2 => conv!(i16),
+        "i" if width == 4 => conv!(i32),
+        "i" if width == 8 => conv!(i64),
+        "u" if width == 1 => conv!(u8),
+        "u" if width == 2 => conv!(u16),
+        "u" if width == 4 => conv!(u32),
+        "u" if width == 8 => conv!(u64),
+        "b" if width == 1 => buf.iter().map(|&b| f64::from(b != 0)).collect(),
+        _ => return Err(unsupported()),
+    };
+    Ok(data)
+}
+
+/// Reads a `.npy` stream of any float, integer or bool dtype and either
+/// memory order, converting to a C-ordered `f64` array.
+pub fn read_npy<R: Read>(r: &mut R) -> io::Result<ArrayD<f64>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic[..6] != MAGIC {
+        return Err(invalid("not an npy file".to_string()));
+    }
+    let header_len = match magic[6] {
+        1 => {
+            let mut b = [0u8; 2];
+            r.read_exact(&mut b)?;
+            u16::from_le_bytes(b) as usize
+        }
+        2 | 3 => {
+            let mut b = [0u8; 4];
+            r.read_exact(&mut b)?;
+            u32::from_le_bytes(b) as usize
+        }
+        v => return Err(invalid(format!("unsupported npy version {}", v))),
+    };
+    let mut header = vec![0u8; header_len];
+    r.read_exact(&mut header)?;
+    let header = parse_header(&String::from_utf8_lossy(&header))?;
+
+    let count = header
+        .shape
+        .iter()
+        .try_fold(1usize, |n, &d| n.checked_mul(d))
+        .ok_or_else(|| invalid(format!("npy shape {:?} is too large", header.shape)))?;
+    let data = decode(r, &header.descr, count)?;
+    let shape = IxDyn(&header.shape);
+    let a = if header.fortran_order {
+        ArrayD::from_shape_vec(shape.f(), data)
+    } else {
+        ArrayD::from_shape_vec(shape, data)
+    };
+    let a = a.map_err(|e| invalid(e.to_string()))?;
+    Ok(a.as_standard_layout().into_owned())
+}
+
+pub fn load_npy(path: impl AsRef<Path>) -> io::Result<ArrayD<f64>> {
+    read_npy(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn save_npy(path: impl AsRef<Path>, a: &ArrayD<f64>) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_npy(&mut w, a)?;
+    w.flush()
+}
+
+/// Loads a `.npy` file as a new leaf `Variable`.
+pub fn load_variable(path: impl AsRef<Path>) -> io::Result<Rc<RefCell<Variable>>> {
+    Ok(Variable::new(load_npy(path)?))
+}
+
+pub fn save_data(path: impl AsRef<Path>, var: &Rc<RefCell<Variable>>) -> io::Result<()> {
+    save_npy(path, &var.borrow().data)
+}
+
+/// Fails with `NotFound` if the variable has no gradient yet.
+pub fn save_grad(path: impl AsRef<Path>, var: &Rc<RefCell<Variable>>) -> io::Result<()> {
+    match &var.borrow().grad {
+        Some(g) => save_npy(path, g),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "variable has no gradient",
+        )),
+    }
+}
+
+/// Writes a NumPy `.npz` archive with one `<name>.npy` member per array,
+/// deflate-compressed like `np.savez_compressed`.
+pub fn save_npz(path: impl AsRef<Path>, arrays: &[(String, ArrayD<f64>)]) -> io::Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, a) in arrays {
+        zip.start_file(format!("{}.npy", name), options)?;
+        write_npy(&mut zip, a)?;
+    }
+    zip.finish()?.flush()
+}
+
+fn read_npz<R: Read + Seek>(reader: R) -> io::Result<Vec<(String, ArrayD<f64>)>> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut arrays = Vec::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let name = file.name().to_string();
+        let Some(key) = name.strip_suffix(".npy") else {
+            continue;
+        };
+        let key = key.to_string();
+        arrays.push((key, read_npy(&mut file)?));
+    }
+    Ok(arrays)
+}
+
+/// Reads every `.npy` member of an `.npz` archive (stored or deflated),
+/// keyed by its name without the extension.
+pub fn load_npz(path: impl AsRef<Path>) -> io::Result<Vec<(String, ArrayD<f64>)>> {
+    read_npz(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 1.0 npy stream with the given header dict and data bytes.
+    fn npy_bytes(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend([1, 0]);
+        out.extend((header.len() as u16).to_le_bytes());
+        out.extend(header.as_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn read_err(header: &str, data: &[u8]) -> io::ErrorKind {
+        read_npy(&mut &npy_bytes(header, data)[..])
+            .unwrap_err()
+            .kind()
+    }
+
+    #[test]
+    fn round_trip() {
+        let a = ArrayD::from_shape_vec(IxDyn(&[2, 3]), (0..6).map(f64::from).collect()).unwrap();
+        let mut buf = Vec::new();
+        write_npy(&mut buf, &a).unwrap();
+        assert_eq!(read_npy(&mut &buf[..]).unwrap(), a);
+    }
+
+    #[test]
+    fn malformed_descr_is_invalid_data() {
+        for descr in ["", "<", "<f", "?f8", "<x8"] {
+            let header = format!(
+                "{{'descr': '{}', 'fortran_order': False, 'shape': (1,), }}",
+                descr
+            );
+            assert_eq!(
+                read_err(&header, &[0; 8]),
+                io::ErrorKind::InvalidData,
+                "{:?}",
+                descr
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_shape_is_invalid_data() {
+        for shape in ["), }", "\u{e9}(1,), }", "(1,", "(x,), }"] {
+            let header = format!(
+                "{{'descr': '<f8', 'fortran_order': False, 'shape': {}",
+                shape
+            );
+            assert_eq!(
+                read_err(&header, &[0; 8]),
+                io::ErrorKind::InvalidData,
+                "{:?}",
+                shape
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_shape_is_rejected() {
+        let huge = "{'descr': '<f8', 'fortran_order': False, 'shape': (4611686018427387904, 4), }";
+        assert_eq!(read_err(huge, &[]), io::ErrorKind::InvalidData);
+        let overflow =
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (4611686018427387904,), }";
+        assert_eq!(read_err(overflow, &[]), io::ErrorKind::InvalidData);
+        let short = "{'descr': '<f8', 'fortran_order': False, 'shape': (1000000000,), }";
+        assert_eq!(read_err(short, &[0; 16]), io::ErrorKind::UnexpectedEof);
+    }
+}
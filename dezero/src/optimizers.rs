@@ -0,0 +1,969 @@
+use ndarray::{arr0, ArrayD, Axis, Zip};
+
+use std::collections::BTreeMap;
+
+use crate::core::{Parameter, SparseGrad, VariableExt};
+use crate::layers::Layer;
+
+/// Parameters that share a learning-rate multiplier.
+pub struct ParamGroup {
+    pub params: Vec<Parameter>,
+    pub lr_scale: f64,
+}
+
+impl ParamGroup {
+    pub fn new(params: Vec<Parameter>, lr_scale: f64) -> Self {
+        ParamGroup { params, lr_scale }
+    }
+}
+
+/// The parameters an optimizer was set up with. Per-parameter state is keyed
+/// by the index into `params`, so the order is fixed at `setup` time.
+#[derive(Default)]
+pub struct Target {
+    pub params: Vec<Parameter>,
+    pub lr_scales: Vec<f64>,
+    pub hooks: Vec<Box<dyn Hook>>,
+}
+
+/// Everything an optimizer has accumulated, enough to resume it: scalars
+/// such as `lr` as 0-d arrays and per-parameter slots keyed `<slot>/<index>`.
+pub type OptimizerState = BTreeMap<String, ArrayD<f64>>;
+
+fn put_scalar(state: &mut OptimizerState, key: &str, value: f64) {
+    state.insert(key.to_string(), arr0(value).into_dyn());
+}
+
+fn get_scalar(state: &OptimizerState, key: &str) -> Option<f64> {
+    state.get(key).and_then(|a| a.iter().next().copied())
+}
+
+fn put_slots(state: &mut OptimizerState, name: &str, slots: &BTreeMap<usize, ArrayD<f64>>) {
+    for (i, a) in slots {
+        state.insert(format!("{}/{}", name, i), a.clone());
+    }
+}
+
+fn get_slots(state: &OptimizerState, name: &str) -> BTreeMap<usize, ArrayD<f64>> {
+    let prefix = format!("{}/", name);
+    state
+        .iter()
+        .filter_map(|(k, a)| {
+            let i = k.strip_prefix(&prefix)?.parse().ok()?;
+            Some((i, a.clone()))
+        })
+        .collect()
+}
+
+fn lr_state(lr: f64) -> OptimizerState {
+    let mut state = OptimizerState::new();
+    put_scalar(&mut state, "lr", lr);
+    state
+}
+
+pub trait Optimizer {
+    fn target(&self) -> &Target;
+
+    fn target_mut(&mut self) -> &mut Target;
+
+    fn lr(&self) -> f64;
+
+    fn set_lr(&mut self, lr: f64);
+
+    /// Applies one update to `param`, whose gradient is known to be present.
+    /// `lr` is the optimizer's rate scaled by the parameter's group.
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64);
+
+    /// Called once at the start of every `update`, before any parameter moves.
+    fn begin_update(&mut self) {}
+
+    /// The per-parameter state maps, so `update_rows` can narrow them to the
+    /// rows being updated. Stateless optimizers keep the empty default.
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        Vec::new()
+    }
+
+    /// Applies one update to the rows named in `param`'s sparse gradient.
+    /// Those rows, and the same rows of every state slot, are gathered into
+    /// a stand-in parameter, stepped by `update_one` and written back, so
+    /// rows that were not looked up keep their weights and state as they are.
+    fn update_rows(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let (rows, grad) = param.borrow().sparse_grad.as_ref().unwrap().coalesce();
+        let sub = {
+            let p = param.borrow();
+            let data = p.data.select(Axis(0), &rows);
+            let grad = grad.into_shape(data.raw_dim()).unwrap();
+            let sub = Parameter::new(data, "");
+            sub.borrow_mut().grad = Some(grad);
+            sub
+        };
+        let mut full = Vec::new();
+        for states in self.slots_mut() {
+            let part = slot(states, index, &param.borrow().data).select(Axis(0), &rows);
+            full.push(states.insert(index, part).unwrap());
+        }
+        self.update_one(index, &sub, lr);
+        for (states, mut s) in self.slots_mut().into_iter().zip(full) {
+            scatter_rows(&mut s, &rows, &states.remove(&index).unwrap());
+            states.insert(index, s);
+        }
+        scatter_rows(&mut param.borrow_mut().data, &rows, &sub.borrow().data);
+    }
+
+    fn setup(&mut self, model: &dyn Layer) {
+        self.setup_groups(vec![ParamGroup::new(model.params().collect(), 1.0)]);
+    }
+
+    fn setup_groups(&mut self, groups: Vec<ParamGroup>) {
+        let target = self.target_mut();
+        target.params.clear();
+        target.lr_scales.clear();
+        for g in groups {
+            target
+                .lr_scales
+                .extend(std::iter::repeat(g.lr_scale).take(g.params.len()));
+            target.params.extend(g.params);
+        }
+    }
+
+    /// The default covers stateless optimizers, whose only state is `lr`.
+    fn state(&self) -> OptimizerState {
+        lr_state(self.lr())
+    }
+
+    /// Restores what `state` returned. Slot indices refer to the parameter
+    /// order of `setup`, so the optimizer must be set up on the same model.
+    fn load_state(&mut self, state: &OptimizerState) {
+        if let Some(lr) = get_scalar(state, "lr") {
+            self.set_lr(lr);
+        }
+    }
+
+    fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.target_mut().hooks.push(hook);
+    }
+
+    /// Steps every parameter that has a gradient. A parameter that has only
+    /// a sparse gradient goes through `update_rows`; its rows are coalesced
+    /// first so hooks see each row once. When a parameter has both kinds,
+    /// the sparse one is folded into `grad`.
+    fn update(&mut self) {
+        for p in self.target().hooks.iter().flat_map(|h| h.frozen()) {
+            p.cleargrad();
+        }
+        for p in self.target().params.iter() {
+            let mut p = p.borrow_mut();
+            let p = &mut *p;
+            if let Some(sg) = p.sparse_grad.take() {
+                match p.grad.as_mut() {
+                    Some(g) => sg.add_to(g),
+                    None => {
+                        let (rows, values) = sg.coalesce();
+                        p.sparse_grad = Some(SparseGrad { rows, values });
+                    }
+                }
+            }
+        }
+        let params: Vec<Parameter> = self
+            .target()
+            .params
+            .iter()
+            .filter(|p| {
+                let p = p.borrow();
+                p.grad.is_some() || p.sparse_grad.is_some()
+            })
+            .cloned()
+            .collect();
+        for hook in self.target_mut().hooks.iter_mut() {
+            hook.apply(&params);
+        }
+
+        self.begin_update();
+        let lr = self.lr();
+        let target = self.target();
+        let scaled: Vec<(usize, Parameter, f64)> = target
+            .params
+            .iter()
+            .zip(target.lr_scales.iter())
+            .enumerate()
+            .filter(|(_, (p, _))| {
+                let p = p.borrow();
+                p.grad.is_some() || p.sparse_grad.is_some()
+            })
+            .map(|(i, (p, &scale))| (i, p.clone(), lr * scale))
+            .collect();
+        for (i, p, lr) in scaled {
+            if p.borrow().grad.is_some() {
+                self.update_one(i, &p, lr);
+            } else {
+                self.update_rows(i, &p, lr);
+            }
+        }
+    }
+}
+
+/// Runs on the parameters that have gradients, before an optimizer steps.
+/// A parameter has either a dense `grad` or a coalesced `sparse_grad`, and
+/// hooks treat the rows of the latter like the matching rows of the former.
+pub trait Hook {
+    fn apply(&mut self, params: &[Parameter]);
+
+    /// Parameters whose gradients `update` drops before any hook runs, so
+    /// freezing does not depend on the order hooks were added in.
+    fn frozen(&self) -> &[Parameter] {
+        &[]
+    }
+}
+
+/// L2 regularization: adds `rate * W` to every gradient.
+pub struct WeightDecay {
+    pub rate: f64,
+}
+
+impl WeightDecay {
+    pub fn new(rate: f64) -> Self {
+        WeightDecay { rate }
+    }
+}
+
+impl Hook for WeightDecay {
+    fn apply(&mut self, params: &[Parameter]) {
+        for p in params {
+            let mut p = p.borrow_mut();
+            let p = &mut *p;
+            if let Some(g) = p.grad.as_mut() {
+                g.scaled_add(self.rate, &p.data);
+            }
+            if let Some(sg) = p.sparse_grad.as_mut() {
+                let rows = p.data.select(Axis(0), &sg.rows);
+                let rows = rows.into_shape(sg.values.raw_dim()).unwrap();
+                sg.values.scaled_add(self.rate, &rows);
+            }
+        }
+    }
+}
+
+/// Rescales all gradients together so their global L2 norm is at most `max_norm`.
+pub struct ClipGrad {
+    pub max_norm: f64,
+}
+
+impl ClipGrad {
+    pub fn new(max_norm: f64) -> Self {
+        ClipGrad { max_norm }
+    }
+}
+
+impl Hook for ClipGrad {
+    fn apply(&mut self, params: &[Parameter]) {
+        let total_norm = params
+            .iter()
+            .map(|p| {
+                let p = p.borrow();
+                let dense = p.grad.as_ref().map_or(0.0, |g| g.mapv(|v| v * v).sum());
+                let sparse = p
+                    .sparse_grad
+                    .as_ref()
+                    .map_or(0.0, |sg| sg.values.mapv(|v| v * v).sum());
+                dense + sparse
+            })
+            .sum::<f64>()
+            .sqrt();
+        let rate = self.max_norm / (total_norm + 1e-6);
+        if rate < 1.0 {
+            for p in params {
+                let mut p = p.borrow_mut();
+                if let Some(g) = p.grad.as_mut() {
+                    *g *= rate;
+                }
+                if let Some(sg) = p.sparse_grad.as_mut() {
+                    sg.values *= rate;
+                }
+            }
+        }
+    }
+}
+
+/// Clamps every gradient element into `[min, max]`.
+pub struct ClipGradValue {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ClipGradValue {
+    pub fn new(min: f64, max: f64) -> Self {
+        ClipGradValue { min, max }
+    }
+}
+
+impl Hook for ClipGradValue {
+    fn apply(&mut self, params: &[Parameter]) {
+        for p in params {
+            let mut p = p.borrow_mut();
+            if let Some(g) = p.grad.as_mut() {
+                g.mapv_inplace(|v| v.clamp(self.min, self.max));
+            }
+            if let Some(sg) = p.sparse_grad.as_mut() {
+                sg.values.mapv_inplace(|v| v.clamp(self.min, self.max));
+            }
+        }
+    }
+}
+
+/// Drops the gradients of the given parameters so the optimizer skips them.
+/// This happens before every other hook, wherever this one was added.
+pub struct FreezeParam {
+    pub params: Vec<Parameter>,
+}
+
+impl FreezeParam {
+    pub fn new(layers: &[&dyn Layer]) -> Self {
+        FreezeParam {
+            params: layers.iter().flat_map(|l| l.params()).collect(),
+        }
+    }
+}
+
+impl Hook for FreezeParam {
+    /// Nothing left to do: `update` has already dropped the gradients.
+    fn apply(&mut self, _params: &[Parameter]) {}
+
+    fn frozen(&self) -> &[Parameter] {
+        &self.params
+    }
+}
+
+fn slot<'a>(
+    states: &'a mut BTreeMap<usize, ArrayD<f64>>,
+    index: usize,
+    like: &ArrayD<f64>,
+) -> &'a mut ArrayD<f64> {
+    let s = states
+        .entry(index)
+        .or_insert_with(|| ArrayD::zeros(like.raw_dim()));
+    if s.shape() != like.shape() {
+        *s = ArrayD::zeros(like.raw_dim());
+    }
+    s
+}
+
+/// Writes the rows of `part` back into rows `rows` of `full`.
+fn scatter_rows(full: &mut ArrayD<f64>, rows: &[usize], part: &ArrayD<f64>) {
+    for (&r, src) in rows.iter().zip(part.outer_iter()) {
+        full.index_axis_mut(Axis(0), r).assign(&src);
+    }
+}
+
+pub struct SGD {
+    pub lr: f64,
+    target: Target,
+}
+
+impl SGD {
+    pub fn new(lr: f64) -> Self {
+        SGD {
+            lr,
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, _index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        p.data.scaled_add(-lr, grad);
+    }
+}
+
+pub struct MomentumSGD {
+    pub lr: f64,
+    pub momentum: f64,
+    pub vs: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl MomentumSGD {
+    pub fn new(lr: f64, momentum: f64) -> Self {
+        MomentumSGD {
+            lr,
+            momentum,
+            vs: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for MomentumSGD {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let v = slot(&mut self.vs, index, &p.data);
+        let momentum = self.momentum;
+        Zip::from(&mut *v)
+            .and(grad)
+            .for_each(|v, &g| *v = momentum * *v - lr * g);
+        p.data += &*v;
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.vs]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.lr);
+        put_slots(&mut state, "vs", &self.vs);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.lr = get_scalar(state, "lr").unwrap_or(self.lr);
+        self.vs = get_slots(state, "vs");
+    }
+}
+
+/// Momentum SGD with Nesterov's look-ahead gradient, in the
+/// `p += momentum * v - lr * grad` form that needs no extra forward pass.
+pub struct Nesterov {
+    pub lr: f64,
+    pub momentum: f64,
+    pub vs: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl Nesterov {
+    pub fn new(lr: f64, momentum: f64) -> Self {
+        Nesterov {
+            lr,
+            momentum,
+            vs: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for Nesterov {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let v = slot(&mut self.vs, index, &p.data);
+        let momentum = self.momentum;
+        Zip::from(&mut p.data)
+            .and(&mut *v)
+            .and(grad)
+            .for_each(|p, v, &g| {
+                *v = momentum * *v - lr * g;
+                *p += momentum * *v - lr * g;
+            });
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.vs]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.lr);
+        put_slots(&mut state, "vs", &self.vs);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.lr = get_scalar(state, "lr").unwrap_or(self.lr);
+        self.vs = get_slots(state, "vs");
+    }
+}
+
+pub struct AdaGrad {
+    pub lr: f64,
+    pub eps: f64,
+    pub hs: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl AdaGrad {
+    pub fn new(lr: f64) -> Self {
+        AdaGrad {
+            lr,
+            eps: 1e-8,
+            hs: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for AdaGrad {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let h = slot(&mut self.hs, index, &p.data);
+        let eps = self.eps;
+        Zip::from(&mut p.data)
+            .and(&mut *h)
+            .and(grad)
+            .for_each(|p, h, &g| {
+                *h += g * g;
+                *p -= lr * g / (h.sqrt() + eps);
+            });
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.hs]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.lr);
+        put_slots(&mut state, "hs", &self.hs);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.lr = get_scalar(state, "lr").unwrap_or(self.lr);
+        self.hs = get_slots(state, "hs");
+    }
+}
+
+/// AdaDelta. `lr` only scales the computed step and is 1.0 in the paper.
+pub struct AdaDelta {
+    pub lr: f64,
+    pub rho: f64,
+    pub eps: f64,
+    pub msg: BTreeMap<usize, ArrayD<f64>>,
+    pub msdx: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl AdaDelta {
+    pub fn new(rho: f64) -> Self {
+        AdaDelta {
+            lr: 1.0,
+            rho,
+            eps: 1e-6,
+            msg: BTreeMap::new(),
+            msdx: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for AdaDelta {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let msg = slot(&mut self.msg, index, &p.data);
+        let msdx = slot(&mut self.msdx, index, &p.data);
+        let (rho, eps) = (self.rho, self.eps);
+        Zip::from(&mut p.data)
+            .and(&mut *msg)
+            .and(&mut *msdx)
+            .and(grad)
+            .for_each(|p, msg, msdx, &g| {
+                *msg = rho * *msg + (1.0 - rho) * g * g;
+                let dx = ((*msdx + eps) / (*msg + eps)).sqrt() * g;
+                *msdx = rho * *msdx + (1.0 - rho) * dx * dx;
+                *p -= lr * dx;
+            });
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.msg, &mut self.msdx]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.lr);
+        put_slots(&mut state, "msg", &self.msg);
+        put_slots(&mut state, "msdx", &self.msdx);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.lr = get_scalar(state, "lr").unwrap_or(self.lr);
+        self.msg = get_slots(state, "msg");
+        self.msdx = get_slots(state, "msdx");
+    }
+}
+
+pub struct RMSprop {
+    pub lr: f64,
+    pub alpha: f64,
+    pub eps: f64,
+    pub ms: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl RMSprop {
+    pub fn new(lr: f64) -> Self {
+        RMSprop {
+            lr,
+            alpha: 0.99,
+            eps: 1e-8,
+            ms: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+}
+
+impl Optimizer for RMSprop {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let ms = slot(&mut self.ms, index, &p.data);
+        let (alpha, eps) = (self.alpha, self.eps);
+        Zip::from(&mut p.data)
+            .and(&mut *ms)
+            .and(grad)
+            .for_each(|p, ms, &g| {
+                *ms = alpha * *ms + (1.0 - alpha) * g * g;
+                *p -= lr * g / (ms.sqrt() + eps);
+            });
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.ms]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.lr);
+        put_slots(&mut state, "ms", &self.ms);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.lr = get_scalar(state, "lr").unwrap_or(self.lr);
+        self.ms = get_slots(state, "ms");
+    }
+}
+
+pub struct Adam {
+    pub alpha: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    /// Decoupled weight decay; zero for plain Adam.
+    pub weight_decay: f64,
+    pub t: u64,
+    pub ms: BTreeMap<usize, ArrayD<f64>>,
+    pub vs: BTreeMap<usize, ArrayD<f64>>,
+    target: Target,
+}
+
+impl Adam {
+    pub fn new(alpha: f64) -> Self {
+        Adam {
+            alpha,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.0,
+            t: 0,
+            ms: BTreeMap::new(),
+            vs: BTreeMap::new(),
+            target: Target::default(),
+        }
+    }
+
+    /// Bias-corrected step size for the current `t` and base rate `alpha`.
+    pub fn step_size(&self, alpha: f64) -> f64 {
+        let fix1 = 1.0 - self.beta1.powi(self.t as i32);
+        let fix2 = 1.0 - self.beta2.powi(self.t as i32);
+        alpha * fix2.sqrt() / fix1
+    }
+}
+
+impl Optimizer for Adam {
+    fn target(&self) -> &Target {
+        &self.target
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        &mut self.target
+    }
+
+    fn lr(&self) -> f64 {
+        self.alpha
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.alpha = lr;
+    }
+
+    fn begin_update(&mut self) {
+        self.t += 1;
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        let mut p = param.borrow_mut();
+        let p = &mut *p;
+        let grad = p.grad.as_ref().unwrap();
+        let step = self.step_size(lr);
+        let m = slot(&mut self.ms, index, &p.data);
+        let v = slot(&mut self.vs, index, &p.data);
+        let (beta1, beta2, eps) = (self.beta1, self.beta2, self.eps);
+        let decay = 1.0 - lr * self.weight_decay;
+        Zip::from(&mut p.data)
+            .and(&mut *m)
+            .and(&mut *v)
+            .and(grad)
+            .for_each(|p, m, v, &g| {
+                *m += (1.0 - beta1) * (g - *m);
+                *v += (1.0 - beta2) * (g * g - *v);
+                *p = *p * decay - step * *m / (v.sqrt() + eps);
+            });
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        vec![&mut self.ms, &mut self.vs]
+    }
+
+    fn state(&self) -> OptimizerState {
+        let mut state = lr_state(self.alpha);
+        put_scalar(&mut state, "t", self.t as f64);
+        put_slots(&mut state, "ms", &self.ms);
+        put_slots(&mut state, "vs", &self.vs);
+        state
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.alpha = get_scalar(state, "lr").unwrap_or(self.alpha);
+        self.t = get_scalar(state, "t").unwrap_or(0.0) as u64;
+        self.ms = get_slots(state, "ms");
+        self.vs = get_slots(state, "vs");
+    }
+}
+
+/// Adam with decoupled weight decay (Loshchilov & Hutter): weights shrink by
+/// `alpha * weight_decay` each step, independently of the gradient moments.
+pub struct AdamW {
+    pub adam: Adam,
+}
+
+impl AdamW {
+    pub fn new(alpha: f64, weight_decay: f64) -> Self {
+        let mut adam = Adam::new(alpha);
+        adam.weight_decay = weight_decay;
+        AdamW { adam }
+    }
+}
+
+impl Optimizer for AdamW {
+    fn target(&self) -> &Target {
+        self.adam.target()
+    }
+
+    fn target_mut(&mut self) -> &mut Target {
+        self.adam.target_mut()
+    }
+
+    fn lr(&self) -> f64 {
+        self.adam.lr()
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.adam.set_lr(lr);
+    }
+
+    fn begin_update(&mut self) {
+        self.adam.begin_update();
+    }
+
+    fn update_one(&mut self, index: usize, param: &Parameter, lr: f64) {
+        self.adam.update_one(index, param, lr);
+    }
+
+    fn slots_mut(&mut self) -> Vec<&mut BTreeMap<usize, ArrayD<f64>>> {
+        self.adam.slots_mut()
+    }
+
+    fn state(&self) -> OptimizerState {
+        self.adam.state()
+    }
+
+    fn load_state(&mut self, state: &OptimizerState) {
+        self.adam.load_state(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::Linear;
+    use ndarray::IxDyn;
+
+    #[test]
+    fn freeze_applies_before_earlier_hooks() {
+        let frozen = Linear::with_in_size(2, 2);
+        let trainable = Parameter::new(ArrayD::zeros(IxDyn(&[2])), "p");
+        let mut params: Vec<Parameter> = frozen.params().collect();
+        params.push(trainable.clone());
+        for p in &params {
+            let shape = p.borrow().data.raw_dim();
+            p.borrow_mut().grad = Some(ArrayD::from_elem(shape, 100.0));
+        }
+        trainable.borrow_mut().grad = Some(ArrayD::from_elem(IxDyn(&[2]), 0.5));
+
+        let mut opt = SGD::new(1.0);
+        opt.setup_groups(vec![ParamGroup::new(params, 1.0)]);
+        opt.add_hook(Box::new(ClipGrad::new(1.0)));
+        opt.add_hook(Box::new(FreezeParam::new(&[&frozen])));
+        opt.update();
+
+        // Norm of the trainable gradient alone is below 1, so no clipping.
+        assert_eq!(
+            trainable.grad().unwrap(),
+            ArrayD::from_elem(IxDyn(&[2]), 0.5)
+        );
+        assert!(frozen.params().all(|p| p.grad().is_none()));
+    }
+
+    type MakeHooks = fn() -> Vec<Box<dyn Hook>>;
+
+    /// The same lookup as `Embedding` and as a one-hot matmul, so the sparse
+    /// and dense gradient paths can be compared.
+    fn embedding_step(hooks: MakeHooks) -> (ArrayD<f64>, ArrayD<f64>) {
+        use crate::functions as F;
+        use crate::layers::Embedding;
+        use crate::Variable;
+
+        let emb = Embedding::new(6, 3);
+        let dense = Parameter::new(emb.w.data(), "W");
+        let ids = [1usize, 4, 1, 1];
+        let mut onehot = ndarray::Array2::<f64>::zeros((ids.len(), 6));
+        for (i, &k) in ids.iter().enumerate() {
+            onehot[[i, k]] = 1.0;
+        }
+        let ids = Variable::new(ndarray::arr1(&[1.0, 4.0, 1.0, 1.0]));
+        F::sum(&F::square(&F::embed_id(&ids, &emb.w))).backward();
+        F::sum(&F::square(&F::matmul(&Variable::new(onehot), &dense))).backward();
+
+        let mut sparse_opt = SGD::new(0.1);
+        sparse_opt.setup(&emb);
+        let mut dense_opt = SGD::new(0.1);
+        dense_opt.setup_groups(vec![ParamGroup::new(vec![dense.clone()], 1.0)]);
+        for (a, b) in hooks().into_iter().zip(hooks()) {
+            sparse_opt.add_hook(a);
+            dense_opt.add_hook(b);
+        }
+        sparse_opt.update();
+        dense_opt.update();
+        (emb.w.data(), dense.data())
+    }
+
+    #[test]
+    fn hooks_see_sparse_gradients() {
+        let cases: Vec<MakeHooks> = vec![
+            || vec![Box::new(ClipGrad::new(0.5))],
+            || vec![Box::new(ClipGradValue::new(-1.0, 1.0))],
+            || vec![Box::new(WeightDecay::new(0.3))],
+        ];
+        for hooks in cases {
+            let (sparse, dense) = embedding_step(hooks);
+            // Weight decay only reaches looked-up rows on the sparse path,
+            // and only those rows moved on either path.
+            for r in [1, 4] {
+                let (a, b) = (sparse.index_axis(Axis(0), r), dense.index_axis(Axis(0), r));
+                assert!(a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-12));
+            }
+        }
+    }
+}
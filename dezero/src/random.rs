@@ -0,0 +1,99 @@
+use ndarray::{ArrayD, IxDyn};
+
+use std::cell::RefCell;
+
+/// SplitMix64 generator. Its whole state is one `u64`, which keeps seeding
+/// and saving/restoring it trivial.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform sample in `[0, 1)`.
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Standard normal sample (Box-Muller).
+    pub fn normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    /// Uniform integer in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.uniform() * n as f64) as usize % n.max(1)
+    }
+
+    pub fn shuffle<T>(&mut self, v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            let j = self.below(i + 1);
+            v.swap(i, j);
+        }
+    }
+
+    pub fn permutation(&mut self, n: usize) -> Vec<usize> {
+        let mut index: Vec<usize> = (0..n).collect();
+        self.shuffle(&mut index);
+        index
+    }
+
+    pub fn randn(&mut self, shape: &[usize]) -> ArrayD<f64> {
+        ArrayD::from_shape_simple_fn(IxDyn(shape), || self.normal())
+    }
+
+    pub fn rand(&mut self, shape: &[usize]) -> ArrayD<f64> {
+        ArrayD::from_shape_simple_fn(IxDyn(shape), || self.uniform())
+    }
+}
+
+thread_local! {
+    static GLOBAL: RefCell<Rng> = const { RefCell::new(Rng { state: 0 }) };
+}
+
+/// Reseeds the global generator used for weight initialization.
+pub fn seed(seed: u64) {
+    GLOBAL.with(|r| r.borrow_mut().set_state(seed));
+}
+
+pub fn get_state() -> u64 {
+    GLOBAL.with(|r| r.borrow().state())
+}
+
+pub fn set_state(state: u64) {
+    GLOBAL.with(|r| r.borrow_mut().set_state(state));
+}
+
+pub fn with_rng<R>(f: impl FnOnce(&mut Rng) -> R) -> R {
+    GLOBAL.with(|r| f(&mut r.borrow_mut()))
+}
+
+pub fn randn(shape: &[usize]) -> ArrayD<f64> {
+    with_rng(|r| r.randn(shape))
+}
+
+pub fn rand(shape: &[usize]) -> ArrayD<f64> {
+    with_rng(|r| r.rand(shape))
+}
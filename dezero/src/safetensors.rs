@@ -0,0 +1,367 @@
+use memmap2::Mmap;
+use ndarray::{ArrayD, IxDyn};
+use serde_json::{Map, Value};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dtype {
+    F64,
+    F32,
+    F16,
+    BF16,
+    I64,
+    I32,
+    I16,
+    I8,
+    U8,
+    Bool,
+}
+
+impl Dtype {
+    fn parse(s: &str) -> io::Result<Self> {
+        Ok(match s {
+            "F64" => Dtype::F64,
+            "F32" => Dtype::F32,
+            "F16" => Dtype::F16,
+            "BF16" => Dtype::BF16,
+            "I64" => Dtype::I64,
+            "I32" => Dtype::I32,
+            "I16" => Dtype::I16,
+            "I8" => Dtype::I8,
+            "U8" => Dtype::U8,
+            "BOOL" => Dtype::Bool,
+            _ => return Err(invalid(format!("unsupported safetensors dtype '{}'", s))),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Dtype::F64 => "F64",
+            Dtype::F32 => "F32",
+            Dtype::F16 => "F16",
+            Dtype::BF16 => "BF16",
+            Dtype::I64 => "I64",
+            Dtype::I32 => "I32",
+            Dtype::I16 => "I16",
+            Dtype::I8 => "I8",
+            Dtype::U8 => "U8",
+            Dtype::Bool => "BOOL",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Dtype::F64 | Dtype::I64 => 8,
+            Dtype::F32 | Dtype::I32 => 4,
+            Dtype::F16 | Dtype::BF16 | Dtype::I16 => 2,
+            Dtype::I8 | Dtype::U8 | Dtype::Bool => 1,
+        }
+    }
+}
+
+fn f16_to_f64(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((bits >> 10) & 0x1f) as i32;
+    let frac = (bits & 0x3ff) as f64;
+    match exp {
+        0 => sign * frac * 2f64.powi(-24),
+        31 if frac == 0.0 => sign * f64::INFINITY,
+        31 => f64::NAN,
+        _ => sign * (1.0 + frac / 1024.0) * 2f64.powi(exp - 15),
+    }
+}
+
+/// A tensor inside a mapped file. `data` borrows the mapping directly.
+pub struct TensorView<'a> {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    pub data: &'a [u8],
+}
+
+impl TensorView<'_> {
+    /// Decodes the little-endian buffer into an owned `f64` array.
+    pub fn to_array(&self) -> ArrayD<f64> {
+        let w = self.dtype.size();
+        let chunks = self.data.chunks_exact(w);
+        let values: Vec<f64> = match self.dtype {
+            Dtype::F64 => chunks
+                .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+                .collect(),
+            Dtype::F32 => chunks
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+                .collect(),
+            Dtype::F16 => chunks
+                .map(|c| f16_to_f64(u16::from_le_bytes([c[0], c[1]])))
+                .collect(),
+            Dtype::BF16 => chunks
+                .map(|c| f32::from_bits((u16::from_le_bytes([c[0], c[1]]) as u32) << 16) as f64)
+                .collect(),
+            Dtype::I64 => chunks
+                .map(|c| i64::from_le_bytes(c.try_into().unwrap()) as f64)
+                .collect(),
+            Dtype::I32 => chunks
+                .map(|c| i32::from_le_bytes(c.try_into().unwrap()) as f64)
+                .collect(),
+            Dtype::I16 => chunks
+                .map(|c| i16::from_le_bytes([c[0], c[1]]) as f64)
+                .collect(),
+            Dtype::I8 => chunks.map(|c| c[0] as i8 as f64).collect(),
+            Dtype::U8 => chunks.map(|c| c[0] as f64).collect(),
+            Dtype::Bool => chunks.map(|c| f64::from(c[0] != 0)).collect(),
+        };
+        ArrayD::from_shape_vec(IxDyn(&self.shape), values).expect("shape checked on open")
+    }
+}
+
+struct Entry {
+    name: String,
+    dtype: Dtype,
+    shape: Vec<usize>,
+    begin: usize,
+    end: usize,
+}
+
+/// A memory-mapped safetensors file. Opening parses and validates only the
+/// JSON header; tensor bytes are read from the mapping when asked for.
+pub struct SafeTensors {
+    mmap: Mmap,
+    data_start: usize,
+    entries: Vec<Entry>,
+}
+
+impl SafeTensors {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // Safety: the mapping is read-only; as with any mmap, the file must
+        // not be truncated by another process while it is in use.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < 8 {
+            return Err(invalid(format!(
+                "{}: too short for safetensors",
+                path.display()
+            )));
+        }
+        let header_len = u64::from_le_bytes(mmap[..8].try_into().unwrap()) as usize;
+        let data_start = 8usize
+            .checked_add(header_len)
+            .filter(|&s| s <= mmap.len())
+            .ok_or_else(|| invalid(format!("{}: header runs past end of file", path.display())))?;
+        let header: Map<String, Value> = serde_json::from_slice(&mmap[8..data_start])
+            .map_err(|e| invalid(format!("{}: bad header: {}", path.display(), e)))?;
+
+        let buffer_len = mmap.len() - data_start;
+        let mut entries = Vec::new();
+        for (name, info) in header {
+            if name == "__metadata__" {
+                continue;
+            }
+            let field = |key: &str| {
+                info.get(key)
+                    .ok_or_else(|| invalid(format!("tensor '{}' has no '{}'", name, key)))
+            };
+            let dtype = Dtype::parse(field("dtype")?.as_str().unwrap_or(""))?;
+            let as_usizes = |v: &Value| -> io::Result<Vec<usize>> {
+                v.as_array()
+                    .ok_or_else(|| invalid(format!("tensor '{}' has a malformed header", name)))?
+                    .iter()
+                    .map(|d| {
+                        d.as_u64().map(|d| d as usize).ok_or_else(|| {
+                            invalid(format!("tensor '{}' has a malformed header", name))
+                        })
+                    })
+                    .collect()
+            };
+            let shape = as_usizes(field("shape")?)?;
+            let offsets = as_usizes(field("data_offsets")?)?;
+            let (begin, end) = match offsets[..] {
+                [b, e] if b <= e && e <= buffer_len => (b, e),
+                _ => return Err(invalid(format!("tensor '{}' has bad data_offsets", name))),
+            };
+            let size = shape
+                .iter()
+                .try_fold(1usize, |n, &d| n.checked_mul(d))
+                .and_then(|n| n.checked_mul(dtype.size()))
+                .ok_or_else(|| invalid(format!("tensor '{}' shape overflows", name)))?;
+            if size != end - begin {
+                return Err(invalid(format!(
+                    "tensor '{}' size does not match its shape",
+                    name
+                )));
+            }
+            entries.push(Entry {
+                name,
+                dtype,
+                shape,
+                begin,
+                end,
+            });
+        }
+        Ok(SafeTensors {
+            mmap,
+            data_start,
+            entries,
+        })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<TensorView<'_>> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| TensorView {
+                dtype: e.dtype,
+                shape: e.shape.clone(),
+                data: &self.mmap[self.data_start + e.begin..self.data_start + e.end],
+            })
+    }
+}
+
+/// Writes `arrays` as a safetensors file, storing values as `F64` or `F32`.
+pub fn save_safetensors(
+    path: impl AsRef<Path>,
+    arrays: &[(String, ArrayD<f64>)],
+    dtype: Dtype,
+) -> io::Result<()> {
+    if dtype != Dtype::F64 && dtype != Dtype::F32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "safetensors output supports F64 and F32 only",
+        ));
+    }
+    let mut header = Map::new();
+    let mut offset = 0;
+    for (name, a) in arrays {
+        let len = a.len() * dtype.size();
+        let mut info = Map::new();
+        info.insert("dtype".to_string(), Value::from(dtype.name()));
+        info.insert("shape".to_string(), Value::from(a.shape().to_vec()));
+        info.insert(
+            "data_offsets".to_string(),
+            Value::from(vec![offset, offset + len]),
+        );
+        header.insert(name.clone(), Value::Object(info));
+        offset += len;
+    }
+    let mut header = serde_json::to_vec(&Value::Object(header)).map_err(io::Error::other)?;
+    // The buffer must start 8-byte aligned; pad the header with spaces.
+    header.resize(header.len().div_ceil(8) * 8, b' ');
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&(header.len() as u64).to_le_bytes())?;
+    w.write_all(&header)?;
+    for (_, a) in arrays {
+        let bytes: Vec<u8> = match dtype {
+            Dtype::F32 => a
+                .as_standard_layout()
+                .iter()
+                .flat_map(|&v| (v as f32).to_le_bytes())
+                .collect(),
+            _ => a
+                .as_standard_layout()
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect(),
+        };
+        w.write_all(&bytes)?;
+    }
+    w.flush()
+}
+
+/// Decodes every tensor in a safetensors file.
+pub fn load_safetensors(path: impl AsRef<Path>) -> io::Result<Vec<(String, ArrayD<f64>)>> {
+    let st = SafeTensors::open(path)?;
+    Ok(st
+        .entries
+        .iter()
+        .map(|e| (e.name.clone(), st.tensor(&e.name).unwrap().to_array()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+    use crate::utils;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("dezero-{}-{}", std::process::id(), name))
+    }
+
+    /// Writes a safetensors file with a hand-written JSON header.
+    fn open_raw(name: &str, header: &str, data: &[u8]) -> io::Result<SafeTensors> {
+        let path = temp_path(name);
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        std::fs::write(&path, bytes)?;
+        let st = SafeTensors::open(&path);
+        std::fs::remove_file(&path)?;
+        st
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Rng::new(0);
+        let arrays = vec![
+            ("a".to_string(), rng.randn(&[2, 3])),
+            ("b.c".to_string(), rng.randn(&[4])),
+            ("scalar".to_string(), ndarray::arr0(1.5).into_dyn()),
+        ];
+        for (dtype, tol) in [(Dtype::F64, 0.0), (Dtype::F32, 1e-6)] {
+            let path = temp_path("round_trip.safetensors");
+            save_safetensors(&path, &arrays, dtype).unwrap();
+            let st = SafeTensors::open(&path).unwrap();
+            assert_eq!(st.tensor("a").unwrap().dtype, dtype);
+            let loaded = load_safetensors(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.len(), arrays.len());
+            for (name, a) in &arrays {
+                let (_, b) = loaded.iter().find(|(n, _)| n == name).unwrap();
+                assert_eq!(a.shape(), b.shape(), "{}", name);
+                assert!(
+                    utils::array_allclose(a, b, tol, 0.0),
+                    "{} {:?}",
+                    name,
+                    dtype
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bad_data_offsets_are_invalid_data() {
+        for offsets in ["[0, 16]", "[8, 0]", "[0]", "[0, 8, 16]", "\"x\""] {
+            let header = format!(
+                r#"{{"w":{{"dtype":"F64","shape":[1],"data_offsets":{}}}}}"#,
+                offsets
+            );
+            let err = open_raw("offsets.safetensors", &header, &[0; 8]).err();
+            assert_eq!(
+                err.map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidData),
+                "{}",
+                offsets
+            );
+        }
+    }
+
+    #[test]
+    fn overflowing_shape_is_invalid_data() {
+        let header =
+            r#"{"w":{"dtype":"F64","shape":[4611686018427387904,8],"data_offsets":[0,0]}}"#;
+        let err = open_raw("overflow.safetensors", header, &[]).err();
+        assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
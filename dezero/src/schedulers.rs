@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use crate::optimizers::Optimizer;
+
+/// Named scalars describing where a scheduler is, enough to resume it.
+pub type SchedulerState = BTreeMap<String, f64>;
+
+/// Adjusts an optimizer's learning rate as training progresses. Whether one
+/// step is an iteration or an epoch is up to the caller.
+pub trait Scheduler {
+    /// Learning rate for the current position in the schedule.
+    fn get_lr(&self) -> f64;
+
+    fn advance(&mut self);
+
+    fn state(&self) -> SchedulerState;
+
+    fn load_state(&mut self, state: &SchedulerState);
+
+    /// Advances by one step and writes the new rate into `optimizer`.
+    fn step(&mut self, optimizer: &mut dyn Optimizer) {
+        self.advance();
+        self.apply(optimizer);
+    }
+
+    /// Writes the current rate into `optimizer` without advancing, e.g.
+    /// before the first update or after `load_state`.
+    fn apply(&self, optimizer: &mut dyn Optimizer) {
+        optimizer.set_lr(self.get_lr());
+    }
+}
+
+fn step_state(last_step: u64) -> SchedulerState {
+    let mut state = SchedulerState::new();
+    state.insert("last_step".to_string(), last_step as f64);
+    state
+}
+
+fn load_step(state: &SchedulerState) -> u64 {
+    state.get("last_step").copied().unwrap_or(0.0) as u64
+}
+
+/// Multiplies the rate by `gamma` every `step_size` steps.
+pub struct StepLR {
+    pub base_lr: f64,
+    pub step_size: u64,
+    pub gamma: f64,
+    pub last_step: u64,
+}
+
+impl StepLR {
+    pub fn new(base_lr: f64, step_size: u64, gamma: f64) -> Self {
+        assert!(step_size > 0, "step_size must be positive");
+        StepLR {
+            base_lr,
+            step_size,
+            gamma,
+            last_step: 0,
+        }
+    }
+}
+
+impl Scheduler for StepLR {
+    fn get_lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi((self.last_step / self.step_size) as i32)
+    }
+
+    fn advance(&mut self) {
+        self.last_step += 1;
+    }
+
+    fn state(&self) -> SchedulerState {
+        step_state(self.last_step)
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        self.last_step = load_step(state);
+    }
+}
+
+/// Multiplies the rate by `gamma` every step.
+pub struct ExponentialLR {
+    pub base_lr: f64,
+    pub gamma: f64,
+    pub last_step: u64,
+}
+
+impl ExponentialLR {
+    pub fn new(base_lr: f64, gamma: f64) -> Self {
+        ExponentialLR {
+            base_lr,
+            gamma,
+            last_step: 0,
+        }
+    }
+}
+
+impl Scheduler for ExponentialLR {
+    fn get_lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi(self.last_step as i32)
+    }
+
+    fn advance(&mut self) {
+        self.last_step += 1;
+    }
+
+    fn state(&self) -> SchedulerState {
+        step_state(self.last_step)
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        self.last_step = load_step(state);
+    }
+}
+
+/// SGDR: cosine decay from `base_lr` to `eta_min` over `t_0` steps, then a
+/// restart; each following period is `t_mult` times longer.
+pub struct CosineAnnealingWarmRestarts {
+    pub base_lr: f64,
+    pub eta_min: f64,
+    pub t_0: u64,
+    pub t_mult: u64,
+    pub last_step: u64,
+}
+
+impl CosineAnnealingWarmRestarts {
+    pub fn new(base_lr: f64, t_0: u64, t_mult: u64, eta_min: f64) -> Self {
+        assert!(t_0 > 0 && t_mult > 0, "t_0 and t_mult must be positive");
+        CosineAnnealingWarmRestarts {
+            base_lr,
+            eta_min,
+            t_0,
+            t_mult,
+            last_step: 0,
+        }
+    }
+}
+
+impl Scheduler for CosineAnnealingWarmRestarts {
+    fn get_lr(&self) -> f64 {
+        let (mut t_cur, mut t_i) = (self.last_step, self.t_0);
+        while t_cur >= t_i {
+            t_cur -= t_i;
+            t_i *= self.t_mult;
+        }
+        let cos = (PI * t_cur as f64 / t_i as f64).cos();
+        self.eta_min + (self.base_lr - self.eta_min) * (1.0 + cos) / 2.0
+    }
+
+    fn advance(&mut self) {
+        self.last_step += 1;
+    }
+
+    fn state(&self) -> SchedulerState {
+        step_state(self.last_step)
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        self.last_step = load_step(state);
+    }
+}
+
+/// Ramps linearly from `start_factor * base_lr` to `base_lr` over
+/// `warmup_steps`, then hands over to `after` (or stays at `base_lr`).
+pub struct LinearWarmup {
+    pub base_lr: f64,
+    pub warmup_steps: u64,
+    pub start_factor: f64,
+    pub after: Option<Box<dyn Scheduler>>,
+    pub last_step: u64,
+}
+
+impl LinearWarmup {
+    pub fn new(base_lr: f64, warmup_steps: u64, start_factor: f64) -> Self {
+        LinearWarmup {
+            base_lr,
+            warmup_steps,
+            start_factor,
+            after: None,
+            last_step: 0,
+        }
+    }
+
+    pub fn then(mut self, after: Box<dyn Scheduler>) -> Self {
+        self.after = Some(after);
+        self
+    }
+}
+
+impl Scheduler for LinearWarmup {
+    fn get_lr(&self) -> f64 {
+        if self.last_step < self.warmup_steps {
+            let frac = self.last_step as f64 / self.warmup_steps as f64;
+            return self.base_lr * (self.start_factor + (1.0 - self.start_factor) * frac);
+        }
+        match &self.after {
+            Some(s) => s.get_lr(),
+            None => self.base_lr,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.last_step += 1;
+        if self.last_step > self.warmup_steps {
+            if let Some(s) = self.after.as_mut() {
+                s.advance();
+            }
+        }
+    }
+
+    fn state(&self) -> SchedulerState {
+        let mut state = step_state(self.last_step);
+        if let Some(s) = &self.after {
+            for (k, v) in s.state() {
+                state.insert(format!("after/{}", k), v);
+            }
+        }
+        state
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        self.last_step = load_step(state);
+        if let Some(s) = self.after.as_mut() {
+            let inner = state
+                .iter()
+                .filter_map(|(k, &v)| k.strip_prefix("after/").map(|k| (k.to_string(), v)))
+                .collect();
+            s.load_state(&inner);
+        }
+    }
+}
+
+/// Cuts the rate by `factor` once the monitored metric (lower is better)
+/// has not improved by more than `threshold` for `patience` reports.
+///
+/// It is driven by `step_metric`; the plain `advance` is a no-op since there
+/// is nothing to decide without a new metric.
+pub struct ReduceLROnPlateau {
+    pub lr: f64,
+    pub factor: f64,
+    pub patience: u64,
+    pub threshold: f64,
+    pub cooldown: u64,
+    pub min_lr: f64,
+    pub best: f64,
+    pub num_bad_steps: u64,
+    pub cooldown_counter: u64,
+}
+
+impl ReduceLROnPlateau {
+    pub fn new(lr: f64, factor: f64, patience: u64) -> Self {
+        ReduceLROnPlateau {
+            lr,
+            factor,
+            patience,
+            threshold: 1e-4,
+            cooldown: 0,
+            min_lr: 0.0,
+            best: f64::INFINITY,
+            num_bad_steps: 0,
+            cooldown_counter: 0,
+        }
+    }
+
+    pub fn step_metric(&mut self, optimizer: &mut dyn Optimizer, metric: f64) {
+        if metric < self.best * (1.0 - self.threshold) {
+            self.best = metric;
+            self.num_bad_steps = 0;
+        } else {
+            self.num_bad_steps += 1;
+        }
+        if self.cooldown_counter > 0 {
+            self.cooldown_counter -= 1;
+            self.num_bad_steps = 0;
+        }
+        if self.num_bad_steps > self.patience {
+            self.lr = (self.lr * self.factor).max(self.min_lr);
+            self.cooldown_counter = self.cooldown;
+            self.num_bad_steps = 0;
+        }
+        self.apply(optimizer);
+    }
+}
+
+impl Scheduler for ReduceLROnPlateau {
+    fn get_lr(&self) -> f64 {
+        self.lr
+    }
+
+    fn advance(&mut self) {}
+
+    fn state(&self) -> SchedulerState {
+        let mut state = SchedulerState::new();
+        state.insert("lr".to_string(), self.lr);
+        state.insert("best".to_string(), self.best);
+        state.insert("num_bad_steps".to_string(), self.num_bad_steps as f64);
+        state.insert("cooldown_counter".to_string(), self.cooldown_counter as f64);
+        state
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        let get = |k: &str, default: f64| state.get(k).copied().unwrap_or(default);
+        self.lr = get("lr", self.lr);
+        self.best = get("best", f64::INFINITY);
+        self.num_bad_steps = get("num_bad_steps", 0.0) as u64;
+        self.cooldown_counter = get("cooldown_counter", 0.0) as u64;
+    }
+}
+
+/// One-cycle policy: cosine ramp from `max_lr / div_factor` up to `max_lr`
+/// over the first `pct_start` of `total_steps`, then cosine decay down to
+/// `max_lr / (div_factor * final_div_factor)`.
+pub struct OneCycleLR {
+    pub max_lr: f64,
+    pub total_steps: u64,
+    pub pct_start: f64,
+    pub div_factor: f64,
+    pub final_div_factor: f64,
+    pub last_step: u64,
+}
+
+impl OneCycleLR {
+    pub fn new(max_lr: f64, total_steps: u64) -> Self {
+        OneCycleLR {
+            max_lr,
+            total_steps,
+            pct_start: 0.3,
+            div_factor: 25.0,
+            final_div_factor: 1e4,
+            last_step: 0,
+        }
+    }
+}
+
+fn cosine_anneal(start: f64, end: f64, pct: f64) -> f64 {
+    end + (start - end) / 2.0 * ((PI * pct).cos() + 1.0)
+}
+
+impl Scheduler for OneCycleLR {
+    fn get_lr(&self) -> f64 {
+        let initial = self.max_lr / self.div_factor;
+        let min_lr = initial / self.final_div_factor;
+        let up_end = (self.pct_start * self.total_steps as f64 - 1.0).max(1.0);
+        let down_end = (self.total_steps as f64 - 1.0).max(up_end + 1.0);
+        let t = (self.last_step as f64).min(down_end);
+        if t <= up_end {
+            cosine_anneal(initial, self.max_lr, t / up_end)
+        } else {
+            cosine_anneal(self.max_lr, min_lr, (t - up_end) / (down_end - up_end))
+        }
+    }
+
+    fn advance(&mut self) {
+        self.last_step += 1;
+    }
+
+    fn state(&self) -> SchedulerState {
+        step_state(self.last_step)
+    }
+
+    fn load_state(&mut self, state: &SchedulerState) {
+        self.last_step = load_step(state);
+    }
+}
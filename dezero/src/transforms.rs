@@ -0,0 +1,32 @@
+use ndarray::ArrayD;
+
+use crate::datasets::Transform;
+
+/// Applies `transforms` left to right.
+pub fn compose(transforms: Vec<Transform>) -> Transform {
+    Box::new(move |x| transforms.iter().fold(x, |x, f| f(x)))
+}
+
+/// `(x - mean) / std`.
+pub fn normalize(mean: f64, std: f64) -> Transform {
+    Box::new(move |x: ArrayD<f64>| (x - mean) / std)
+}
+
+/// Multiplies by a constant, e.g. `1.0 / 255.0` for 8-bit pixels.
+pub fn scale(factor: f64) -> Transform {
+    Box::new(move |x: ArrayD<f64>| x * factor)
+}
+
+/// Collapses a sample to one dimension.
+pub fn flatten() -> Transform {
+    Box::new(|x: ArrayD<f64>| {
+        let n = x.len();
+        crate::utils::reshape(&x, &[n])
+    })
+}
+
+/// Reshapes a sample, e.g. a flat 784 vector to `[1, 28, 28]`.
+pub fn reshape(shape: &[usize]) -> Transform {
+    let shape = shape.to_vec();
+    Box::new(move |x: ArrayD<f64>| crate::utils::reshape(&x, &shape))
+}
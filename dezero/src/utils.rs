@@ -0,0 +1,123 @@
+use ndarray::{ArrayD, Axis, IxDyn};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::core::{no_grad, Variable, VariableExt};
+
+/// Sums `x` down to `shape`, undoing numpy-style broadcasting.
+pub fn sum_to(x: &ArrayD<f64>, shape: &[usize]) -> ArrayD<f64> {
+    if x.shape() == shape {
+        return x.clone();
+    }
+    let lead = x.ndim() - shape.len();
+    let mut y = x.clone();
+    for _ in 0..lead {
+        y = y.sum_axis(Axis(0));
+    }
+    for (i, &s) in shape.iter().enumerate() {
+        if s == 1 && y.shape()[i] != 1 {
+            y = y.sum_axis(Axis(i)).insert_axis(Axis(i));
+        }
+    }
+    y
+}
+
+pub fn broadcast_to(x: &ArrayD<f64>, shape: &[usize]) -> ArrayD<f64> {
+    x.broadcast(IxDyn(shape))
+        .unwrap_or_else(|| panic!("cannot broadcast {:?} to {:?}", x.shape(), shape))
+        .to_owned()
+}
+
+/// Reshapes the upstream gradient of a sum so that it broadcasts back
+/// over the reduced axes of the input.
+pub fn reshape_sum_backward(
+    gy: &ArrayD<f64>,
+    x_shape: &[usize],
+    axis: &Option<Vec<usize>>,
+    keepdims: bool,
+) -> ArrayD<f64> {
+    if keepdims || x_shape.is_empty() {
+        return gy.clone();
+    }
+    let shape: Vec<usize> = match axis {
+        None => vec![1; x_shape.len()],
+        Some(axes) => x_shape
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| if axes.contains(&i) { 1 } else { s })
+            .collect(),
+    };
+    reshape(gy, &shape)
+}
+
+/// Reshapes into a fresh C-ordered array regardless of the input layout.
+pub fn reshape(x: &ArrayD<f64>, shape: &[usize]) -> ArrayD<f64> {
+    x.as_standard_layout()
+        .into_owned()
+        .into_shape(IxDyn(shape))
+        .unwrap_or_else(|_| panic!("cannot reshape {:?} to {:?}", x.shape(), shape))
+}
+
+/// Central-difference gradient of `sum(f(x))` with respect to `x`.
+pub fn numerical_grad<F>(f: &F, x: &ArrayD<f64>, eps: f64) -> ArrayD<f64>
+where
+    F: Fn(&Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>>,
+{
+    let _guard = no_grad();
+    let mut grad = ArrayD::zeros(x.raw_dim());
+    let mut xp = x.clone();
+    for i in 0..x.len() {
+        let orig = xp.as_slice_mut().unwrap()[i];
+        xp.as_slice_mut().unwrap()[i] = orig + eps;
+        let y1 = f(&Variable::new(xp.clone())).borrow().data.sum();
+        xp.as_slice_mut().unwrap()[i] = orig - eps;
+        let y2 = f(&Variable::new(xp.clone())).borrow().data.sum();
+        xp.as_slice_mut().unwrap()[i] = orig;
+        grad.as_slice_mut().unwrap()[i] = (y1 - y2) / (2.0 * eps);
+    }
+    grad
+}
+
+/// Compares the backward pass of `f` at `x` with `numerical_grad`.
+pub fn gradient_check<F>(f: &F, x: &ArrayD<f64>, rtol: f64, atol: f64) -> bool
+where
+    F: Fn(&Rc<RefCell<Variable>>) -> Rc<RefCell<Variable>>,
+{
+    let x = x.as_standard_layout().into_owned();
+    let num_grad = numerical_grad(f, &x, 1e-6);
+    let var = Variable::new(x);
+    let y = f(&var);
+    let y = crate::functions::sum(&y);
+    y.backward();
+    let grad = var.grad().expect("no gradient reached the input");
+    array_allclose(&grad, &num_grad, rtol, atol)
+}
+
+pub fn array_allclose(a: &ArrayD<f64>, b: &ArrayD<f64>, rtol: f64, atol: f64) -> bool {
+    a.shape() == b.shape()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(&a, &b)| (a - b).abs() <= atol + rtol * b.abs())
+}
+
+/// Shared helpers for the gradient tests in `functions` and `functions_conv`.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::random::Rng;
+
+    pub(crate) type Var = Rc<RefCell<Variable>>;
+
+    pub(crate) fn randn(shape: &[usize], seed: u64) -> ArrayD<f64> {
+        Rng::new(seed).randn(shape)
+    }
+
+    /// `f(x) * c` for a fixed random `c`: a plain sum of a normalized output
+    /// has zero gradient, and even elsewhere weighting every output element
+    /// differently makes `gradient_check` see more than the column sums.
+    pub(crate) fn weighted(y: Var, seed: u64) -> Var {
+        let c = randn(y.borrow().shape(), seed);
+        crate::functions::mul(&y, &Variable::new(c))
+    }
+}